@@ -1,19 +1,100 @@
-use std::io::Read;
+use aiff::{AiffError, AiffStream, ParseMode};
+use std::io::{Cursor, Read, Seek};
 
 fn main() {
-    let mut data = Vec::new();
-    std::io::stdin().read_to_end(&mut data).unwrap();
+    let mut args = std::env::args().skip(1).peekable();
+    let mode = match args.peek().map(String::as_str) {
+        Some("--strict") => ParseMode::Strict,
+        Some("--lenient") => ParseMode::Lenient,
+        _ => ParseMode::Normal,
+    };
+    if mode != ParseMode::Normal {
+        args.next();
+    }
 
-    let aiff = aiff::AiffReader::all().read(&data).unwrap();
-    println!("{:#?}", aiff.comm);
-    println!("audio length: {:?}", aiff.comm.audio_length());
+    // files are streamed, but standard input can't be seeked through
+    let result = match args.next() {
+        Some(path) => std::fs::File::open(path)
+            .map_err(AiffError::from)
+            .and_then(|file| inspect(file, mode)),
+        None => {
+            let mut data = Vec::new();
+            std::io::stdin().read_to_end(&mut data).unwrap();
+            inspect(Cursor::new(data), mode)
+        }
+    };
+    if let Err(err) = result {
+        eprintln!("error: {}", err);
+        std::process::exit(1);
+    }
+}
+
+fn inspect<R: Read + Seek>(reader: R, mode: ParseMode) -> aiff::Result<()> {
+    let mut aiff = AiffStream::with_mode(reader, mode)?;
+    for warning in aiff.warnings() {
+        println!("warning: {}", warning);
+    }
+    println!("form type: {:?}", aiff.form_type());
+    if let Some(fver) = aiff.fver()? {
+        println!("format version: 0x{:08x}", fver.timestamp);
+    }
+    println!("{:#?}", aiff.comm());
+    println!("audio length: {:?}", aiff.comm().audio_length());
+
+    let mark = aiff.mark()?;
+    println!("MARK: {:#?}", mark);
+    println!("INST: {:#?}", aiff.inst()?);
+    if let Some(codes) = aiff.vadpcm_codes()? {
+        println!("VADPCM codebook: {:#?}", codes.book);
+    }
+    if let Some(loops) = aiff.vadpcm_loops()? {
+        println!("VADPCM loops: {:#?}", loops.loops);
+    }
 
-    println!("MARK: {:#?}", aiff.mark);
-    println!("INST: {:#?}", aiff.inst);
+    for (label, chunk) in [
+        ("name", aiff.name()?),
+        ("author", aiff.author()?),
+        ("copyright", aiff.copyright()?),
+    ]
+    .iter()
+    {
+        if let Some(chunk) = chunk {
+            println!("{}: {}", label, chunk.text);
+        }
+    }
+    for annotation in aiff.annotations()? {
+        println!("annotation: {}", annotation.text);
+    }
+
+    if let Some(comt) = aiff.comt()? {
+        for comment in comt.comments {
+            let marker = comment
+                .marker
+                .and_then(|id| mark.as_ref()?.marker(id))
+                .map(|marker| format!(" (at marker {:?})", marker.name))
+                .unwrap_or_default();
+            println!("comment{}: {}", marker, comment.text);
+        }
+    }
+
+    for midi in aiff.midi()? {
+        println!("MIDI data: {} bytes", midi.data.len());
+    }
+    if let Some(aesd) = aiff.aesd()? {
+        println!("AES channel status: {:02x?}", aesd.aes_channel_status_data);
+    }
+    for appl in aiff.appl()? {
+        println!(
+            "application-specific data for {}: {} bytes",
+            appl.signature,
+            appl.data.len()
+        );
+    }
 
-    print!("other chunks:");
-    for id in aiff.other_chunks.keys() {
-        print!(" {}", id);
+    print!("chunks:");
+    for entry in aiff.chunks() {
+        print!(" {}", entry.id);
     }
     println!();
+    Ok(())
 }
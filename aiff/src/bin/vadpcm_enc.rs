@@ -0,0 +1,89 @@
+//! Reads an AIFF file from standard input, designs a codebook for it and encodes it to a mono
+//! VADPCM AIFC file, keeping its markers and instrument data. With `--bits2`, raw 2-bit frames are
+//! written instead, as AIFC has no compression type for them. The codebook and loop, if any, are
+//! printed in the SDK's text format.
+
+use aiff::chunks::comm::{CommonChunk, Compression};
+use aiff::chunks::fver::FormatVersionChunk;
+use aiff::chunks::inst::LoopRange;
+use aiff::chunks::ssnd::SoundDataChunk;
+use aiff::chunks::vadpcm::{VadpcmCodesChunk, VadpcmLoopsChunk};
+use aiff::{Aiff, Dither, FormType};
+use vadpcm::{Codebook, DesignOptions, Encoder, FrameFormat, INFINITE_LOOP, SAMPLES_PER_FRAME};
+
+fn main() {
+    let mut args: Vec<String> = std::env::args().collect();
+    let bits2 = args.iter().any(|arg| arg == "--bits2");
+    args.retain(|arg| arg != "--bits2");
+    if args.len() != 2 {
+        eprintln!("usage: {} [--bits2] <output file> < [AIFF file]", args[0]);
+        std::process::exit(1);
+    }
+
+    let aiff = aiff::AiffReader::all().read_from(std::io::stdin()).unwrap();
+    let samples: Vec<i16> = aiff
+        .samples()
+        .unwrap()
+        .with_dither(Dither::default())
+        .frames()
+        .downmix()
+        .collect();
+
+    let book = Codebook::design(&samples, &DesignOptions::default()).unwrap();
+    let format = if bits2 {
+        FrameFormat::Bits2
+    } else {
+        FrameFormat::Bits4
+    };
+    let mut encoder = Encoder::new(book.clone(), format);
+    let (encoded, adpcm_loop) = match aiff.sustain_loop() {
+        Some(LoopRange { start, end, .. }) => {
+            let (encoded, adpcm_loop) = encoder.encode_looped(&samples, start, end, INFINITE_LOOP);
+            println!(
+                "loop {} {} {}",
+                adpcm_loop.start, adpcm_loop.end, adpcm_loop.count
+            );
+            print_rows(&adpcm_loop.state);
+            (encoded, Some(adpcm_loop))
+        }
+        None => (encoder.encode(&samples), None),
+    };
+
+    println!("{}\n{}", book.order(), book.npredictors());
+    for predictor in 0..book.npredictors() {
+        print_rows(book.predictor(predictor));
+    }
+
+    if bits2 {
+        std::fs::write(&args[1], encoded).unwrap();
+        return;
+    }
+
+    let comm = CommonChunk {
+        num_channels: 1,
+        num_sample_frames: (encoded.len() / FrameFormat::Bits4.frame_size() * SAMPLES_PER_FRAME)
+            as u32,
+        sample_size: 16,
+        sample_rate: aiff.comm.sample_rate,
+        compression: Some(Compression::vadpcm()),
+    };
+    let mut output = Aiff::new(FormType::Aifc, comm, SoundDataChunk::new(&encoded));
+    output.fver = Some(FormatVersionChunk::default());
+    output.mark = aiff.mark;
+    output.inst = aiff.inst;
+    output.vadpcm_codes = Some(VadpcmCodesChunk { version: 1, book });
+    output.vadpcm_loops = adpcm_loop.map(|adpcm_loop| VadpcmLoopsChunk {
+        version: 1,
+        loops: vec![adpcm_loop],
+    });
+
+    let mut file = std::fs::File::create(&args[1]).unwrap();
+    output.write(&mut file).unwrap();
+}
+
+fn print_rows(values: &[i16]) {
+    for row in values.chunks(8) {
+        let row: Vec<String> = row.iter().map(|value| format!("{:6}", value)).collect();
+        println!("{}", row.join(" "));
+    }
+}
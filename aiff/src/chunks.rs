@@ -1,4 +1,11 @@
+pub mod aesd;
+pub mod appl;
 pub mod comm;
+pub mod comt;
+pub mod fver;
 pub mod inst;
 pub mod mark;
+pub mod midi;
 pub mod ssnd;
+pub mod text;
+pub mod vadpcm;
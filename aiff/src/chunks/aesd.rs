@@ -0,0 +1,28 @@
+use crate::error::*;
+use std::convert::TryInto;
+use std::io::Write;
+
+/// The AES channel status data of a digital recording.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AudioRecordingChunk {
+    pub aes_channel_status_data: [u8; 24],
+}
+
+impl AudioRecordingChunk {
+    const SIZE: usize = 24;
+
+    pub(crate) fn read(data: &[u8]) -> Result<Self> {
+        if data.len() < Self::SIZE {
+            return Err(AiffError::UnexpectedEnd);
+        }
+
+        Ok(Self {
+            aes_channel_status_data: data[..Self::SIZE].try_into().unwrap(),
+        })
+    }
+
+    pub(crate) fn write(&self, w: &mut impl Write) -> Result<()> {
+        w.write_all(&self.aes_channel_status_data)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,31 @@
+use crate::error::*;
+use crate::types::*;
+use std::convert::TryInto;
+use std::io::Write;
+
+/// Data specific to an application, identified by its signature. VADPCM chunks are parsed
+/// separately.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ApplicationSpecificChunk {
+    pub signature: ID,
+    pub data: Vec<u8>,
+}
+
+impl ApplicationSpecificChunk {
+    pub(crate) fn read(data: &[u8]) -> Result<Self> {
+        if data.len() < 4 {
+            return Err(AiffError::UnexpectedEnd);
+        }
+
+        Ok(Self {
+            signature: data[0..4].try_into()?,
+            data: data[4..].to_vec(),
+        })
+    }
+
+    pub(crate) fn write(&self, w: &mut impl Write) -> Result<()> {
+        w.write_all(self.signature.data())?;
+        w.write_all(&self.data)?;
+        Ok(())
+    }
+}
@@ -1,36 +1,117 @@
 use crate::error::*;
 use crate::types::*;
-use byteorder::{ByteOrder, BE};
+use byteorder::{ByteOrder, WriteBytesExt, BE};
+use std::convert::TryInto;
+use std::io::Write;
 use std::time::Duration;
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct CommonChunk {
     pub num_channels: u16,
     pub num_sample_frames: u32,
     pub sample_size: u16,
     pub sample_rate: f64,
+    /// Only present in AIFF-C files.
+    pub compression: Option<Compression>,
+}
+
+/// The compression type of the sound data, along with its human-readable name.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Compression {
+    pub id: ID,
+    pub name: String,
+}
+
+impl Compression {
+    /// Uncompressed big-endian samples, as in plain AIFF files.
+    pub const NONE: ID = ID::new(*b"NONE");
+    /// Uncompressed little-endian samples.
+    pub const SOWT: ID = ID::new(*b"sowt");
+    /// N64 VADPCM with 4-bit residuals.
+    pub const VAPC: ID = ID::new(*b"VAPC");
+
+    /// No compression, with its usual name.
+    pub fn none() -> Self {
+        Self {
+            id: Self::NONE,
+            name: "not compressed".to_string(),
+        }
+    }
+
+    /// VADPCM compression, with the name the SDK tools give it.
+    pub fn vadpcm() -> Self {
+        Self {
+            id: Self::VAPC,
+            name: "VADPCM ~4-1".to_string(),
+        }
+    }
 }
 
 impl CommonChunk {
-    pub(crate) fn read(data: &[u8]) -> Result<Self> {
-        if data.len() != 18 {
-            return Err(AiffError::InvalidFormat);
+    pub(crate) fn read(data: &[u8], form_type: FormType) -> Result<Self> {
+        let valid_len = match form_type {
+            FormType::Aiff => data.len() == 18,
+            FormType::Aifc => data.len() >= 23,
+        };
+        if !valid_len {
+            return Err(AiffError::UnexpectedEnd);
         }
 
         let num_channels = BE::read_u16(&data[0..2]);
         let num_sample_frames = BE::read_u32(&data[2..6]);
         let sample_size = BE::read_u16(&data[6..8]);
-        let sample_rate = read_f80(&data[8..18]);
+        let sample_rate = read_f80(&data[8..18])?;
+
+        let compression = match form_type {
+            FormType::Aiff => None,
+            FormType::Aifc => {
+                let id = data[18..22].try_into()?;
+                let name = read_pstring(&mut &data[22..])?;
+                Some(Compression { id, name })
+            }
+        };
 
         Ok(CommonChunk {
             num_channels,
             num_sample_frames,
             sample_size,
             sample_rate,
+            compression,
         })
     }
 
-    pub fn audio_length(&self) -> Duration {
-        Duration::from_secs_f64(self.num_sample_frames as f64 / self.sample_rate)
+    pub(crate) fn write(&self, w: &mut impl Write, form_type: FormType) -> Result<()> {
+        w.write_u16::<BE>(self.num_channels)?;
+        w.write_u32::<BE>(self.num_sample_frames)?;
+        w.write_u16::<BE>(self.sample_size)?;
+        w.write_all(&write_f80(self.sample_rate))?;
+
+        match form_type {
+            FormType::Aiff => {
+                let id = self.compression_type();
+                if id != Compression::NONE {
+                    return Err(AiffError::CompressionRequiresAifc(id));
+                }
+            }
+            FormType::Aifc => {
+                let none = Compression::none();
+                let compression = self.compression.as_ref().unwrap_or(&none);
+                w.write_all(compression.id.data())?;
+                write_pstring(w, &compression.name)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// `None` if the sample rate isn't positive and finite.
+    pub fn audio_length(&self) -> Option<Duration> {
+        Duration::try_from_secs_f64(self.num_sample_frames as f64 / self.sample_rate).ok()
+    }
+
+    /// The compression type, `NONE` for plain AIFF files.
+    pub fn compression_type(&self) -> ID {
+        self.compression
+            .as_ref()
+            .map_or(Compression::NONE, |compression| compression.id)
     }
 }
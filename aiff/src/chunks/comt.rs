@@ -0,0 +1,87 @@
+use crate::error::*;
+use byteorder::{ByteOrder, WriteBytesExt, BE};
+use std::io::Write;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Comment {
+    /// Seconds since January 1, 1904.
+    pub timestamp: u32,
+    /// The ID of the marker the comment is about, if any.
+    pub marker: Option<u16>,
+    pub text: String,
+}
+
+impl Comment {
+    fn read(data: &mut &[u8]) -> Result<Self> {
+        if data.len() < 8 {
+            return Err(AiffError::UnexpectedEnd);
+        }
+
+        let timestamp = BE::read_u32(&data[0..4]);
+        let marker = match BE::read_u16(&data[4..6]) {
+            0 => None,
+            id => Some(id),
+        };
+        let count = BE::read_u16(&data[6..8]) as usize;
+        *data = &data[8..];
+
+        if data.len() < count {
+            return Err(AiffError::UnexpectedEnd);
+        }
+        let text = String::from_utf8_lossy(&data[..count]).into_owned();
+        // pad to an even length
+        *data = &data[(count + count % 2).min(data.len())..];
+
+        Ok(Self {
+            timestamp,
+            marker,
+            text,
+        })
+    }
+
+    fn write(&self, w: &mut impl Write) -> Result<()> {
+        if self.text.len() > u16::MAX as usize {
+            return Err(AiffError::StringTooLong);
+        }
+
+        w.write_u32::<BE>(self.timestamp)?;
+        w.write_u16::<BE>(self.marker.unwrap_or(0))?;
+        w.write_u16::<BE>(self.text.len() as u16)?;
+        w.write_all(self.text.as_bytes())?;
+        if self.text.len() % 2 == 1 {
+            w.write_u8(0)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CommentsChunk {
+    pub comments: Vec<Comment>,
+}
+
+impl CommentsChunk {
+    pub(crate) fn read(data: &[u8]) -> Result<Self> {
+        if data.len() < 2 {
+            return Err(AiffError::UnexpectedEnd);
+        }
+
+        let num_comments = BE::read_u16(&data[0..2]);
+
+        let mut comments = Vec::with_capacity(num_comments as usize);
+        let mut data = &data[2..];
+        for _ in 0..num_comments {
+            comments.push(Comment::read(&mut data)?);
+        }
+
+        Ok(Self { comments })
+    }
+
+    pub(crate) fn write(&self, w: &mut impl Write) -> Result<()> {
+        w.write_u16::<BE>(self.comments.len() as u16)?;
+        for comment in &self.comments {
+            comment.write(w)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,37 @@
+use crate::error::*;
+use byteorder::{ByteOrder, WriteBytesExt, BE};
+use std::io::Write;
+
+/// The version of the AIFF-C specification a file follows.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FormatVersionChunk {
+    pub timestamp: u32,
+}
+
+impl FormatVersionChunk {
+    /// The only version there is, from May 23, 1990.
+    pub const AIFC_VERSION_1: u32 = 0xa280_5140;
+
+    pub(crate) fn read(data: &[u8]) -> Result<Self> {
+        if data.len() != 4 {
+            return Err(AiffError::UnexpectedEnd);
+        }
+
+        Ok(Self {
+            timestamp: BE::read_u32(data),
+        })
+    }
+
+    pub(crate) fn write(&self, w: &mut impl Write) -> Result<()> {
+        w.write_u32::<BE>(self.timestamp)?;
+        Ok(())
+    }
+}
+
+impl Default for FormatVersionChunk {
+    fn default() -> Self {
+        Self {
+            timestamp: Self::AIFC_VERSION_1,
+        }
+    }
+}
@@ -1,8 +1,10 @@
+use super::mark::MarkerChunk;
 use crate::error::*;
-use byteorder::{ByteOrder, BE};
+use byteorder::{ByteOrder, WriteBytesExt, BE};
 use std::convert::TryInto;
+use std::io::Write;
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum PlayMode {
     NoLooping,
     ForwardLooping,
@@ -22,7 +24,17 @@ impl std::convert::TryFrom<u16> for PlayMode {
     }
 }
 
-#[derive(Debug)]
+impl From<&PlayMode> for u16 {
+    fn from(play_mode: &PlayMode) -> u16 {
+        match play_mode {
+            PlayMode::NoLooping => 0,
+            PlayMode::ForwardLooping => 1,
+            PlayMode::ForwardBackwardLooping => 2,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
 pub struct Loop {
     pub play_mode: PlayMode,
     pub begin_loop: u16,
@@ -34,7 +46,7 @@ impl Loop {
 
     fn read(data: &[u8]) -> Result<Self> {
         if data.len() < Self::SIZE {
-            return Err(AiffError::InvalidFormat);
+            return Err(AiffError::UnexpectedEnd);
         }
 
         Ok(Self {
@@ -43,9 +55,53 @@ impl Loop {
             end_loop: BE::read_u16(&data[4..6]),
         })
     }
+
+    /// Looks up the loop's markers, giving the sample frames it covers. Loops that don't loop,
+    /// point to missing markers or are empty give `None`.
+    pub fn resolve(&self, mark: &MarkerChunk) -> Option<LoopRange> {
+        if self.play_mode == PlayMode::NoLooping {
+            return None;
+        }
+
+        let start = mark.marker(self.begin_loop)?.position;
+        let end = mark.marker(self.end_loop)?.position;
+        if start >= end {
+            return None;
+        }
+
+        Some(LoopRange {
+            play_mode: self.play_mode,
+            start,
+            end,
+        })
+    }
+
+    fn write(&self, w: &mut impl Write) -> Result<()> {
+        w.write_u16::<BE>((&self.play_mode).into())?;
+        w.write_u16::<BE>(self.begin_loop)?;
+        w.write_u16::<BE>(self.end_loop)?;
+        Ok(())
+    }
 }
 
-#[derive(Debug)]
+/// A loop resolved to the sample frames `start..end`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LoopRange {
+    pub play_mode: PlayMode,
+    pub start: u32,
+    pub end: u32,
+}
+
+/// How to play a note out of an instrument.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct NoteSettings {
+    /// Ratio to speed the samples up by.
+    pub pitch_ratio: f64,
+    /// Factor to scale the samples by.
+    pub amplitude: f32,
+}
+
+#[derive(Debug, Clone, PartialEq)]
 pub struct InstrumentChunk {
     pub base_note: i8,
     pub detune: i8,
@@ -63,7 +119,7 @@ impl InstrumentChunk {
 
     pub(crate) fn read(data: &[u8]) -> Result<Self> {
         if data.len() < Self::SIZE {
-            return Err(AiffError::InvalidFormat);
+            return Err(AiffError::UnexpectedEnd);
         }
 
         Ok(Self {
@@ -73,9 +129,44 @@ impl InstrumentChunk {
             high_note: data[3] as i8,
             low_velocity: data[4] as i8,
             high_velocity: data[5] as i8,
-            gain: BE::read_i16(&data[6..8]) as i16,
+            gain: BE::read_i16(&data[6..8]),
             sustain_loop: Loop::read(&data[8..14])?,
             release_loop: Loop::read(&data[14..20])?,
         })
     }
+
+    /// Whether the instrument is meant to play the given MIDI note at the given velocity.
+    pub fn accepts(&self, note: u8, velocity: u8) -> bool {
+        let (note, velocity) = (note as i8, velocity as i8);
+        (self.low_note..=self.high_note).contains(&note)
+            && (self.low_velocity..=self.high_velocity).contains(&velocity)
+    }
+
+    /// How to play the given MIDI note at the given velocity: the samples are pitched relative
+    /// to the base note and detune, and scaled by the gain and by the velocity, linearly.
+    pub fn note(&self, note: u8, velocity: u8) -> Result<NoteSettings> {
+        if note > 127 || velocity > 127 || !self.accepts(note, velocity) {
+            return Err(AiffError::NoteOutOfRange { note, velocity });
+        }
+
+        let cents = (note as i32 - self.base_note as i32) * 100 + self.detune as i32;
+        Ok(NoteSettings {
+            pitch_ratio: 2f64.powf(cents as f64 / 1200.0),
+            amplitude: 10f32.powf(self.gain as f32 / 20.0) * velocity as f32 / 127.0,
+        })
+    }
+
+    pub(crate) fn write(&self, w: &mut impl Write) -> Result<()> {
+        w.write_all(&[
+            self.base_note as u8,
+            self.detune as u8,
+            self.low_note as u8,
+            self.high_note as u8,
+            self.low_velocity as u8,
+            self.high_velocity as u8,
+        ])?;
+        w.write_i16::<BE>(self.gain)?;
+        self.sustain_loop.write(w)?;
+        self.release_loop.write(w)
+    }
 }
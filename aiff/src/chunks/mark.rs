@@ -1,8 +1,9 @@
 use crate::error::*;
 use crate::types::*;
-use byteorder::{ByteOrder, BE};
+use byteorder::{ByteOrder, WriteBytesExt, BE};
+use std::io::Write;
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct Marker {
     pub id: u16,
     pub position: u32,
@@ -12,7 +13,7 @@ pub struct Marker {
 impl Marker {
     fn read(data: &mut &[u8]) -> Result<Self> {
         if data.len() < 6 {
-            return Err(AiffError::InvalidFormat);
+            return Err(AiffError::UnexpectedEnd);
         }
 
         let id = BE::read_u16(&data[0..2]);
@@ -22,9 +23,15 @@ impl Marker {
 
         Ok(Marker { id, position, name })
     }
+
+    fn write(&self, w: &mut impl Write) -> Result<()> {
+        w.write_u16::<BE>(self.id)?;
+        w.write_u32::<BE>(self.position)?;
+        write_pstring(w, &self.name)
+    }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct MarkerChunk {
     pub markers: Vec<Marker>,
 }
@@ -32,7 +39,7 @@ pub struct MarkerChunk {
 impl MarkerChunk {
     pub(crate) fn read(data: &[u8]) -> Result<Self> {
         if data.len() < 2 {
-            return Err(AiffError::InvalidFormat);
+            return Err(AiffError::UnexpectedEnd);
         }
 
         let num_markers = BE::read_u16(&data[0..2]);
@@ -45,4 +52,16 @@ impl MarkerChunk {
 
         Ok(Self { markers })
     }
+
+    pub fn marker(&self, id: u16) -> Option<&Marker> {
+        self.markers.iter().find(|marker| marker.id == id)
+    }
+
+    pub(crate) fn write(&self, w: &mut impl Write) -> Result<()> {
+        w.write_u16::<BE>(self.markers.len() as u16)?;
+        for marker in &self.markers {
+            marker.write(w)?;
+        }
+        Ok(())
+    }
 }
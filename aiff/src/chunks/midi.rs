@@ -0,0 +1,21 @@
+use crate::error::*;
+use std::io::Write;
+
+/// Raw MIDI data, such as system exclusive messages for a sampler.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MidiDataChunk {
+    pub data: Vec<u8>,
+}
+
+impl MidiDataChunk {
+    pub(crate) fn read(data: &[u8]) -> Result<Self> {
+        Ok(Self {
+            data: data.to_vec(),
+        })
+    }
+
+    pub(crate) fn write(&self, w: &mut impl Write) -> Result<()> {
+        w.write_all(&self.data)?;
+        Ok(())
+    }
+}
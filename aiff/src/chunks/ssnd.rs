@@ -1,31 +1,74 @@
 use crate::error::*;
-use byteorder::{ByteOrder, BE};
+use byteorder::{ByteOrder, WriteBytesExt, BE};
+use std::borrow::Cow;
+use std::io::Write;
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct SoundDataChunk<'a> {
-    data: &'a [u8],
+    /// Amount of bytes skipped before the sound data, usually to align it.
+    pub offset: u32,
+    /// The size of the blocks the sound data is aligned to, if any. Purely informative.
+    pub block_size: u32,
+    data: Cow<'a, [u8]>,
 }
 
 impl<'a> SoundDataChunk<'a> {
+    /// Wraps raw sound data, already encoded as described by the common chunk.
+    pub fn new(data: impl Into<Cow<'a, [u8]>>) -> Self {
+        Self {
+            offset: 0,
+            block_size: 0,
+            data: data.into(),
+        }
+    }
+
+    /// Size of the offset and block size fields before the sound data.
+    pub(crate) const HEADER_SIZE: usize = 8;
+
     pub(crate) fn read(data: &'a [u8]) -> Result<Self> {
-        if data.len() < 8 {
-            return Err(AiffError::InvalidFormat);
+        let (offset, block_size) = Self::read_header(data)?;
+        let start = Self::HEADER_SIZE + offset as usize;
+        if data.len() < start {
+            return Err(AiffError::UnexpectedEnd);
         }
 
-        let offset = BE::read_u32(&data[0..4]);
-        if offset != 0 {
-            unimplemented!("nonzero sound data offset");
+        Ok(Self {
+            offset,
+            block_size,
+            data: Cow::Borrowed(&data[start..]),
+        })
+    }
+
+    /// Reads the offset and block size.
+    pub(crate) fn read_header(data: &[u8]) -> Result<(u32, u32)> {
+        if data.len() < Self::HEADER_SIZE {
+            return Err(AiffError::UnexpectedEnd);
         }
+
+        let offset = BE::read_u32(&data[0..4]);
         let block_size = BE::read_u32(&data[4..8]);
-        if block_size != 0 {
-            unimplemented!("nonzero sound data block size");
-        }
+        Ok((offset, block_size))
+    }
 
-        let data = &data[8..];
-        Ok(Self { data })
+    pub(crate) fn write(&self, w: &mut impl Write) -> Result<()> {
+        w.write_u32::<BE>(self.offset)?;
+        w.write_u32::<BE>(self.block_size)?;
+        w.write_all(&vec![0; self.offset as usize])?;
+        w.write_all(&self.data)?;
+        Ok(())
     }
 
-    pub fn raw_data(&self) -> &'a [u8] {
-        self.data
+    /// The sound data, past the offset.
+    pub fn raw_data(&self) -> &[u8] {
+        &self.data
+    }
+
+    /// Copies the sound data if it's borrowed.
+    pub fn into_owned(self) -> SoundDataChunk<'static> {
+        SoundDataChunk {
+            offset: self.offset,
+            block_size: self.block_size,
+            data: Cow::Owned(self.data.into_owned()),
+        }
     }
 }
@@ -0,0 +1,22 @@
+//! The name, author, copyright and annotation chunks, which only hold text.
+
+use crate::error::*;
+use std::io::Write;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TextChunk {
+    pub text: String,
+}
+
+impl TextChunk {
+    pub(crate) fn read(data: &[u8]) -> Result<Self> {
+        Ok(Self {
+            text: String::from_utf8_lossy(data).into_owned(),
+        })
+    }
+
+    pub(crate) fn write(&self, w: &mut impl Write) -> Result<()> {
+        w.write_all(self.text.as_bytes())?;
+        Ok(())
+    }
+}
@@ -0,0 +1,137 @@
+//! The application-specific chunks holding VADPCM data, with the `stoc` signature.
+
+use crate::error::*;
+use crate::types::*;
+use byteorder::{ByteOrder, WriteBytesExt, BE};
+use std::io::Write;
+use vadpcm::{Codebook, Loop};
+
+/// The signature of every chunk in this module.
+pub const SIGNATURE: &[u8; 4] = b"stoc";
+
+/// Reads the signature and name of an application-specific chunk, returning them along with
+/// the rest of its data.
+pub(crate) fn read_appl_header(data: &[u8]) -> Result<(ID, String, &[u8])> {
+    if data.len() < 4 {
+        return Err(AiffError::UnexpectedEnd);
+    }
+    let signature = ID::new([data[0], data[1], data[2], data[3]]);
+
+    let mut data = &data[4..];
+    let name = if signature.data() == SIGNATURE {
+        read_pstring(&mut data)?
+    } else {
+        String::new()
+    };
+    Ok((signature, name, data))
+}
+
+fn write_appl_header(w: &mut impl Write, name: &str) -> Result<()> {
+    w.write_all(SIGNATURE)?;
+    write_pstring(w, name)
+}
+
+/// The codebook used to decode the sound data.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VadpcmCodesChunk {
+    pub version: u16,
+    pub book: Codebook,
+}
+
+impl VadpcmCodesChunk {
+    pub const NAME: &'static str = "VADPCMCODES";
+
+    pub(crate) fn read(data: &[u8]) -> Result<Self> {
+        if data.len() < 6 {
+            return Err(AiffError::UnexpectedEnd);
+        }
+
+        let version = BE::read_u16(&data[0..2]);
+        let order = BE::read_u16(&data[2..4]) as usize;
+        let npredictors = BE::read_u16(&data[4..6]) as usize;
+
+        let data = &data[6..];
+        let len = order * npredictors * 8;
+        if data.len() < len * 2 {
+            return Err(AiffError::UnexpectedEnd);
+        }
+        let mut coefs = vec![0; len];
+        BE::read_i16_into(&data[..len * 2], &mut coefs);
+
+        Ok(Self {
+            version,
+            book: Codebook::new(order, npredictors, coefs)?,
+        })
+    }
+
+    /// Writes the whole chunk data, including the signature and name.
+    pub(crate) fn write(&self, w: &mut impl Write) -> Result<()> {
+        write_appl_header(w, Self::NAME)?;
+        w.write_u16::<BE>(self.version)?;
+        w.write_u16::<BE>(self.book.order() as u16)?;
+        w.write_u16::<BE>(self.book.npredictors() as u16)?;
+        for &coef in self.book.coefs() {
+            w.write_i16::<BE>(coef)?;
+        }
+        Ok(())
+    }
+}
+
+/// The loops of the sound data, along with the decoder state at their start.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VadpcmLoopsChunk {
+    pub version: u16,
+    pub loops: Vec<Loop>,
+}
+
+impl VadpcmLoopsChunk {
+    pub const NAME: &'static str = "VADPCMLOOPS";
+    const LOOP_SIZE: usize = 44;
+
+    pub(crate) fn read(data: &[u8]) -> Result<Self> {
+        if data.len() < 4 {
+            return Err(AiffError::UnexpectedEnd);
+        }
+
+        let version = BE::read_u16(&data[0..2]);
+        let num_loops = BE::read_u16(&data[2..4]) as usize;
+
+        let data = &data[4..];
+        if data.len() < num_loops * Self::LOOP_SIZE {
+            return Err(AiffError::UnexpectedEnd);
+        }
+
+        let loops = data
+            .chunks_exact(Self::LOOP_SIZE)
+            .take(num_loops)
+            .map(|data| {
+                let mut state = [0; 16];
+                BE::read_i16_into(&data[12..44], &mut state);
+                Loop {
+                    start: BE::read_u32(&data[0..4]),
+                    end: BE::read_u32(&data[4..8]),
+                    count: BE::read_u32(&data[8..12]),
+                    state,
+                }
+            })
+            .collect();
+
+        Ok(Self { version, loops })
+    }
+
+    /// Writes the whole chunk data, including the signature and name.
+    pub(crate) fn write(&self, w: &mut impl Write) -> Result<()> {
+        write_appl_header(w, Self::NAME)?;
+        w.write_u16::<BE>(self.version)?;
+        w.write_u16::<BE>(self.loops.len() as u16)?;
+        for adpcm_loop in &self.loops {
+            w.write_u32::<BE>(adpcm_loop.start)?;
+            w.write_u32::<BE>(adpcm_loop.end)?;
+            w.write_u32::<BE>(adpcm_loop.count)?;
+            for &sample in &adpcm_loop.state {
+                w.write_i16::<BE>(sample)?;
+            }
+        }
+        Ok(())
+    }
+}
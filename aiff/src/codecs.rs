@@ -0,0 +1,181 @@
+//! Decoders for the compression types of AIFF-C files other than VADPCM.
+
+use byteorder::{ByteOrder, BE};
+
+/// Size of an IMA ADPCM packet for a single channel.
+pub(crate) const IMA4_PACKET_SIZE: usize = 34;
+/// Amount of samples in an IMA ADPCM packet.
+pub(crate) const IMA4_SAMPLES_PER_PACKET: usize = 64;
+
+const IMA_STEPS: [i32; 89] = [
+    7, 8, 9, 10, 11, 12, 13, 14, 16, 17, 19, 21, 23, 25, 28, 31, 34, 37, 41, 45, 50, 55, 60, 66,
+    73, 80, 88, 97, 107, 118, 130, 143, 157, 173, 190, 209, 230, 253, 279, 307, 337, 371, 408, 449,
+    494, 544, 598, 658, 724, 796, 876, 963, 1060, 1166, 1282, 1411, 1552, 1707, 1878, 2066, 2272,
+    2499, 2749, 3024, 3327, 3660, 4026, 4428, 4871, 5358, 5894, 6484, 7132, 7845, 8630, 9493,
+    10442, 11487, 12635, 13899, 15289, 16818, 18500, 20350, 22385, 24623, 27086, 29794, 32767,
+];
+
+const IMA_INDEX_STEPS: [i32; 8] = [-1, -1, -1, -1, 2, 4, 6, 8];
+
+/// Converts a float sample, full scale being from -1 to 1, to a 32-bit one, clipping it.
+fn float_to_i32(value: f64) -> i32 {
+    // NaN becomes 0
+    (value * 2_147_483_648.0).round() as i32
+}
+
+/// Decodes a big-endian `fl32` sample to 32 bits.
+pub(crate) fn decode_float32(data: &[u8]) -> i32 {
+    float_to_i32(BE::read_f32(data) as f64)
+}
+
+/// Decodes a big-endian `fl64` sample to 32 bits.
+pub(crate) fn decode_float64(data: &[u8]) -> i32 {
+    float_to_i32(BE::read_f64(data))
+}
+
+/// Decodes a G.711 µ-law sample to 16 bits.
+pub(crate) fn decode_ulaw(value: u8) -> i32 {
+    let value = !value;
+    let exponent = (value >> 4) & 7;
+    let mantissa = (value & 0x0f) as i32;
+
+    let magnitude = (((mantissa << 3) + 0x84) << exponent) - 0x84;
+    if value & 0x80 != 0 {
+        -magnitude
+    } else {
+        magnitude
+    }
+}
+
+/// Decodes a G.711 A-law sample to 16 bits.
+pub(crate) fn decode_alaw(value: u8) -> i32 {
+    let value = value ^ 0x55;
+    let exponent = (value >> 4) & 7;
+    let mantissa = (value & 0x0f) as i32;
+
+    let magnitude = if exponent == 0 {
+        (mantissa << 4) + 8
+    } else {
+        ((mantissa << 4) + 0x108) << (exponent - 1)
+    };
+    // unlike µ-law, the sign bit is set for positive samples
+    if value & 0x80 != 0 {
+        magnitude
+    } else {
+        -magnitude
+    }
+}
+
+/// Decodes a packet of 64 IMA ADPCM samples per channel to 16 bits, as Apple stores them: one
+/// packet per channel, each starting with the predictor and step index.
+pub(crate) fn decode_ima4(data: &[u8], num_channels: usize, out: &mut Vec<i32>) {
+    let start = out.len();
+    out.resize(start + IMA4_SAMPLES_PER_PACKET * num_channels, 0);
+
+    for (channel, packet) in data.chunks_exact(IMA4_PACKET_SIZE).enumerate() {
+        let header = BE::read_u16(&packet[0..2]);
+        let mut predictor = (header & 0xff80) as i16 as i32;
+        let mut index = ((header & 0x7f) as usize).min(IMA_STEPS.len() - 1);
+
+        let nibbles = packet[2..]
+            .iter()
+            .flat_map(|&byte| [byte & 0x0f, byte >> 4]);
+        for (i, nibble) in nibbles.enumerate() {
+            let step = IMA_STEPS[index];
+            let mut diff = step >> 3;
+            if nibble & 1 != 0 {
+                diff += step >> 2;
+            }
+            if nibble & 2 != 0 {
+                diff += step >> 1;
+            }
+            if nibble & 4 != 0 {
+                diff += step;
+            }
+            if nibble & 8 != 0 {
+                diff = -diff;
+            }
+
+            predictor = (predictor + diff).clamp(i16::MIN as i32, i16::MAX as i32);
+            index = (index as i32 + IMA_INDEX_STEPS[(nibble & 7) as usize])
+                .clamp(0, IMA_STEPS.len() as i32 - 1) as usize;
+            out[start + i * num_channels + channel] = predictor;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ulaw() {
+        // from the G.711 reference decoding table
+        let first_row = [
+            -32124, -31100, -30076, -29052, -28028, -27004, -25980, -24956, -23932, -22908, -21884,
+            -20860, -19836, -18812, -17788, -16764,
+        ];
+        let last_row = [
+            -120, -112, -104, -96, -88, -80, -72, -64, -56, -48, -40, -32, -24, -16, -8, 0,
+        ];
+        for i in 0..16 {
+            assert_eq!(decode_ulaw(i as u8), first_row[i]);
+            assert_eq!(decode_ulaw(0x70 + i as u8), last_row[i]);
+            // positive samples mirror them
+            assert_eq!(decode_ulaw(0x80 + i as u8), -first_row[i]);
+            assert_eq!(decode_ulaw(0xf0 + i as u8), -last_row[i]);
+        }
+        assert_eq!(decode_ulaw(0x10), -15996);
+        assert_eq!(decode_ulaw(0x3f), -1980);
+        assert_eq!(decode_ulaw(0xc0), 1884);
+    }
+
+    #[test]
+    fn alaw() {
+        // from the G.711 reference decoding table
+        let first_row = [
+            -5504, -5248, -6016, -5760, -4480, -4224, -4992, -4736, -7552, -7296, -8064, -7808,
+            -6528, -6272, -7040, -6784,
+        ];
+        let row_0x50 = [
+            -88, -72, -120, -104, -24, -8, -56, -40, -216, -200, -248, -232, -152, -136, -184, -168,
+        ];
+        for i in 0..16 {
+            assert_eq!(decode_alaw(i as u8), first_row[i]);
+            assert_eq!(decode_alaw(0x50 + i as u8), row_0x50[i]);
+            // positive samples mirror them
+            assert_eq!(decode_alaw(0x80 + i as u8), -first_row[i]);
+            assert_eq!(decode_alaw(0xd0 + i as u8), -row_0x50[i]);
+        }
+        assert_eq!(decode_alaw(0x45), -264);
+        assert_eq!(decode_alaw(0x2a), -32256);
+        assert_eq!(decode_alaw(0xaa), 32256);
+    }
+
+    #[test]
+    fn ima4() {
+        let mut data = [0; 2 * IMA4_PACKET_SIZE];
+        // predictor 0, step index 0; nibbles 7, 0, 15 and 8, then zeroes
+        data[2] = 0x07;
+        data[3] = 0x8f;
+        // predictor 256, step index 5; all zeroes
+        data[IMA4_PACKET_SIZE] = 0x01;
+        data[IMA4_PACKET_SIZE + 1] = 0x05;
+
+        let mut out = vec![-1];
+        decode_ima4(&data, 2, &mut out);
+        assert_eq!(out.len(), 1 + 2 * IMA4_SAMPLES_PER_PACKET);
+        assert_eq!(out[0], -1);
+
+        let left: Vec<i32> = out[1..].iter().step_by(2).copied().collect();
+        let right: Vec<i32> = out[2..].iter().step_by(2).copied().collect();
+        // worked out by hand: each zero nibble adds an eighth of the step and lowers the index
+        assert_eq!(
+            left[..18],
+            [11, 13, -12, -15, -12, -9, -7, -5, -3, -1, 1, 2, 3, 4, 5, 6, 7, 8]
+        );
+        assert!(left[18..].iter().all(|&sample| sample == 8));
+        assert_eq!(right[..5], [257, 258, 259, 260, 261]);
+        assert!(right[5..].iter().all(|&sample| sample == 261));
+    }
+}
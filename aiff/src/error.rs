@@ -1,20 +1,69 @@
 use crate::types::ID;
+use crate::validation::Violations;
 use thiserror::Error;
+use vadpcm::VadpcmError;
 
 #[derive(Error, Debug)]
 pub enum AiffError {
-    #[error("invalid format")]
-    InvalidFormat,
-    #[error("form type is not AIFF but {0}")]
+    #[error("invalid format at offset {offset}: {reason}")]
+    InvalidFormat { offset: u64, reason: &'static str },
+    #[error("invalid {id} chunk at offset {offset}: {source}")]
+    InvalidChunk {
+        id: ID,
+        /// Offset of the chunk's data.
+        offset: u64,
+        source: Box<AiffError>,
+    },
+    #[error("file breaks the spec: {0}")]
+    Violations(Violations),
+    #[error("unexpected end of chunk data")]
+    UnexpectedEnd,
+    #[error("invalid ID {0:02x?}")]
+    InvalidId([u8; 4]),
+    #[error("form type is not AIFF or AIFC but {0}")]
     InvalidFormType(ID),
+    #[error("unsupported compression type {0}")]
+    UnsupportedCompression(ID),
 
     #[error("missing common chunk")]
     MissingComm,
     #[error("missing sound data chunk")]
     MissingSsnd,
 
+    #[error("80-bit float is invalid or out of range")]
+    InvalidFloat,
     #[error("invalid play mode {0}")]
     InvalidPlayMode(u16),
+    #[error("note {note} at velocity {velocity} is outside of the instrument's range")]
+    NoteOutOfRange { note: u8, velocity: u8 },
+
+    #[error("unsupported sample size {0}")]
+    InvalidSampleSize(u16),
+    #[error("missing VADPCM codebook")]
+    MissingVadpcmCodes,
+    #[error("compression type {0} requires an AIFF-C file")]
+    CompressionRequiresAifc(ID),
+    #[error("string is longer than 255 bytes")]
+    StringTooLong,
+
+    #[error("i/o error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("vadpcm error: {0}")]
+    Vadpcm(#[from] VadpcmError),
+}
+
+impl AiffError {
+    /// Locates an error that happened while reading a chunk.
+    pub(crate) fn in_chunk(self, id: ID, offset: u64) -> Self {
+        match self {
+            AiffError::InvalidChunk { .. } => self,
+            source => AiffError::InvalidChunk {
+                id,
+                offset,
+                source: Box::new(source),
+            },
+        }
+    }
 }
 
 pub type Result<T> = std::result::Result<T, AiffError>;
@@ -1,28 +1,46 @@
-//! Basic support for Audio IFF file reading.
+//! Basic support for Audio IFF and AIFF-C file reading and writing.
 
 mod error;
 pub use error::{AiffError, Result};
 
 mod types;
-pub use types::ID;
 use types::*;
+pub use types::{read_f80, write_f80, ChunkKind, FormType, RawChunk, ID};
 
 pub mod chunks;
 use chunks::*;
 
-use byteorder::{ByteOrder, BE};
-use std::collections::HashMap;
-use std::convert::TryInto;
+mod codecs;
+mod looping;
+pub use looping::LoopedFrames;
+mod pitch;
+pub use pitch::Pitched;
+mod samples;
+pub use samples::{requantize, Dither, Frames, Sample, Samples};
+
+mod stream;
+pub use stream::{AiffStream, ChunkEntry, StreamFrames, StreamSamples};
+mod validation;
+pub use validation::{ParseMode, Violation, Violations};
+
+use std::borrow::Cow;
+use std::io::{Cursor, Read, Write};
+use std::path::Path;
 
 #[derive(Debug, Default)]
 pub struct AiffReader {
     pub read_mark: bool,
     pub read_inst: bool,
-    //pub read_midi: bool,
-    //pub read_aesd: bool,
-    //pub read_appl: bool,
-    //pub read_comt: bool,
+    pub read_midi: bool,
+    pub read_aesd: bool,
+    /// VADPCM application-specific chunks are always read, as they're needed to decode the
+    /// sound data.
+    pub read_appl: bool,
+    pub read_comt: bool,
+    /// Name, author, copyright and annotation chunks.
+    pub read_text: bool,
     pub read_other: bool,
+    pub mode: ParseMode,
 }
 
 impl AiffReader {
@@ -30,151 +48,598 @@ impl AiffReader {
         Self {
             read_mark: true,
             read_inst: true,
-            //read_midi: true,
-            //read_aesd: true,
-            //read_appl: true,
-            //read_comt: true,
+            read_midi: true,
+            read_aesd: true,
+            read_appl: true,
+            read_comt: true,
+            read_text: true,
             read_other: true,
+            mode: ParseMode::default(),
         }
     }
 
     pub fn read<'a>(&self, data: &'a [u8]) -> Result<Aiff<'a>> {
-        Aiff::read(data, &self)
+        Aiff::read(data, self)
+    }
+
+    /// Reads a file whole from a reader.
+    pub fn read_from(&self, mut reader: impl Read) -> Result<OwnedAiff> {
+        let mut data = Vec::new();
+        reader.read_to_end(&mut data)?;
+        Ok(self.read(&data)?.into_owned())
+    }
+
+    pub fn read_path(&self, path: impl AsRef<Path>) -> Result<OwnedAiff> {
+        let data = std::fs::read(path)?;
+        Ok(self.read(&data)?.into_owned())
     }
 }
 
-#[derive(Debug)]
+/// A file that owns its data, rather than borrowing the buffer it was read from.
+pub type OwnedAiff = Aiff<'static>;
+
+#[derive(Debug, Clone)]
 pub struct Aiff<'a> {
+    pub form_type: FormType,
+    /// Only read from AIFF-C files, which are meant to have one. Written only when set, so new
+    /// AIFF-C files need it set to `FormatVersionChunk::default()`.
+    pub fver: Option<fver::FormatVersionChunk>,
     pub comm: comm::CommonChunk,
     pub ssnd: ssnd::SoundDataChunk<'a>,
     pub mark: Option<mark::MarkerChunk>,
     pub inst: Option<inst::InstrumentChunk>,
-    //pub midi: Option<midi::MidiDataChunk>,
-    //pub aesd: Option<aesd::AudioRecordingChunk>,
-    //pub appl: Option<appl::ApplicationSpecificChunk>,
-    //pub comt: Option<comt::CommentsChunk>,
-    pub other_chunks: HashMap<ID, &'a [u8]>,
+    pub vadpcm_codes: Option<vadpcm::VadpcmCodesChunk>,
+    pub vadpcm_loops: Option<vadpcm::VadpcmLoopsChunk>,
+    pub midi: Vec<midi::MidiDataChunk>,
+    pub aesd: Option<aesd::AudioRecordingChunk>,
+    /// Application-specific chunks other than the VADPCM ones.
+    pub appl: Vec<appl::ApplicationSpecificChunk>,
+    pub comt: Option<comt::CommentsChunk>,
+    pub name: Option<text::TextChunk>,
+    pub author: Option<text::TextChunk>,
+    pub copyright: Option<text::TextChunk>,
+    pub annotations: Vec<text::TextChunk>,
+    /// Every chunk read, in order, including unknown ones. Writing the file keeps the chunks in
+    /// this order, and those that weren't modified as they were.
+    pub chunks: Vec<RawChunk<'a>>,
+    /// Violations of the spec found while reading the file, outside of normal mode.
+    pub warnings: Vec<Violation>,
 }
 
 impl<'a> Aiff<'a> {
-    pub(crate) fn read(data: &'a [u8], config: &AiffReader) -> Result<Self> {
-        let mut data = data;
-        let (form_id, form_data) = read_chunk(&mut data)?;
-        if form_id.data() != b"FORM" {
-            return Err(AiffError::InvalidFormat);
+    /// A file with only the required chunks, to be filled in before writing it.
+    pub fn new(
+        form_type: FormType,
+        comm: comm::CommonChunk,
+        ssnd: ssnd::SoundDataChunk<'a>,
+    ) -> Self {
+        Self {
+            form_type,
+            fver: None,
+            comm,
+            ssnd,
+            mark: None,
+            inst: None,
+            vadpcm_codes: None,
+            vadpcm_loops: None,
+            midi: Vec::new(),
+            aesd: None,
+            appl: Vec::new(),
+            comt: None,
+            name: None,
+            author: None,
+            copyright: None,
+            annotations: Vec::new(),
+            chunks: Vec::new(),
+            warnings: Vec::new(),
         }
+    }
 
-        let data = form_data;
-        let form_type: ID = data[0..4].try_into()?;
-        if form_type.data() != b"AIFF" {
-            return Err(AiffError::InvalidFormType(form_type));
-        }
+    pub(crate) fn read(data: &'a [u8], config: &AiffReader) -> Result<Self> {
+        let stream = AiffStream::with_mode(Cursor::new(data), config.mode)?;
+        let form_type = stream.form_type();
 
+        let mut fver = None;
         let mut comm = None;
         let mut ssnd = None;
         let mut mark = None;
         let mut inst = None;
-        let mut other_chunks = HashMap::new();
+        let mut vadpcm_codes = None;
+        let mut vadpcm_loops = None;
+        let mut midi = Vec::new();
+        let mut aesd = None;
+        let mut appl = Vec::new();
+        let mut comt = None;
+        let mut name = None;
+        let mut author = None;
+        let mut copyright = None;
+        let mut annotations = Vec::new();
+        let mut chunks: Vec<RawChunk<'a>> = Vec::new();
 
-        let mut data = &data[4..];
-        while !data.is_empty() {
-            let (chunk_id, chunk_data) = read_chunk(&mut data)?;
+        for entry in stream.chunks() {
+            let chunk_id = entry.id;
+            let chunk_data = &data[entry.offset as usize..][..entry.size as usize];
 
-            match chunk_id.data() {
-                b"COMM" => {
-                    comm = Some(comm::CommonChunk::read(chunk_data)?);
-                }
-                b"SSND" => {
-                    ssnd = Some(ssnd::SoundDataChunk::read(chunk_data)?);
-                }
+            let mut read_chunk = || -> Result<ChunkKind> {
+                Ok(match chunk_id.data() {
+                    b"FVER" if form_type == FormType::Aifc => {
+                        fver = Some(fver::FormatVersionChunk::read(chunk_data)?);
+                        ChunkKind::Fver
+                    }
+                    b"COMM" => {
+                        comm = Some(comm::CommonChunk::read(chunk_data, form_type)?);
+                        ChunkKind::Comm
+                    }
+                    b"SSND" => {
+                        ssnd = Some(ssnd::SoundDataChunk::read(chunk_data)?);
+                        ChunkKind::Ssnd
+                    }
 
-                b"MARK" => {
-                    if config.read_mark {
+                    b"MARK" if config.read_mark => {
                         mark = Some(mark::MarkerChunk::read(chunk_data)?);
+                        ChunkKind::Mark
                     }
-                }
-                b"INST" => {
-                    if config.read_inst {
+                    b"INST" if config.read_inst => {
                         inst = Some(inst::InstrumentChunk::read(chunk_data)?);
+                        ChunkKind::Inst
+                    }
+
+                    b"APPL" => {
+                        let (signature, name, appl_data) = vadpcm::read_appl_header(chunk_data)?;
+                        if signature.data() == vadpcm::SIGNATURE
+                            && name == vadpcm::VadpcmCodesChunk::NAME
+                        {
+                            vadpcm_codes = Some(vadpcm::VadpcmCodesChunk::read(appl_data)?);
+                            ChunkKind::VadpcmCodes
+                        } else if signature.data() == vadpcm::SIGNATURE
+                            && name == vadpcm::VadpcmLoopsChunk::NAME
+                        {
+                            vadpcm_loops = Some(vadpcm::VadpcmLoopsChunk::read(appl_data)?);
+                            ChunkKind::VadpcmLoops
+                        } else if config.read_appl {
+                            appl.push(appl::ApplicationSpecificChunk::read(chunk_data)?);
+                            ChunkKind::Appl(appl.len() - 1)
+                        } else {
+                            ChunkKind::Other
+                        }
+                    }
+
+                    b"MIDI" if config.read_midi => {
+                        midi.push(midi::MidiDataChunk::read(chunk_data)?);
+                        ChunkKind::Midi(midi.len() - 1)
+                    }
+                    b"AESD" if config.read_aesd => {
+                        aesd = Some(aesd::AudioRecordingChunk::read(chunk_data)?);
+                        ChunkKind::Aesd
+                    }
+                    b"COMT" if config.read_comt => {
+                        comt = Some(comt::CommentsChunk::read(chunk_data)?);
+                        ChunkKind::Comt
                     }
-                }
 
-                _ => {
-                    if config.read_other {
-                        other_chunks.insert(chunk_id, chunk_data);
+                    b"NAME" | b"AUTH" | b"(c) " | b"ANNO" if config.read_text => {
+                        let chunk = text::TextChunk::read(chunk_data)?;
+                        match chunk_id.data() {
+                            b"NAME" => {
+                                name = Some(chunk);
+                                ChunkKind::Name
+                            }
+                            b"AUTH" => {
+                                author = Some(chunk);
+                                ChunkKind::Author
+                            }
+                            b"(c) " => {
+                                copyright = Some(chunk);
+                                ChunkKind::Copyright
+                            }
+                            _ => {
+                                annotations.push(chunk);
+                                ChunkKind::Annotation(annotations.len() - 1)
+                            }
+                        }
                     }
+
+                    _ => ChunkKind::Other,
+                })
+            };
+            let kind = read_chunk().map_err(|err| err.in_chunk(chunk_id, entry.offset))?;
+
+            if kind == ChunkKind::Other && !config.read_other {
+                continue;
+            }
+            // only the last of duplicated chunks is parsed, so earlier ones are kept as is
+            for chunk in &mut chunks {
+                if chunk.kind == kind {
+                    chunk.kind = ChunkKind::Other;
                 }
             }
+            chunks.push(RawChunk {
+                id: chunk_id,
+                offset: entry.offset,
+                data: Cow::Borrowed(chunk_data),
+                kind,
+            });
         }
 
+        let mut comm = comm.ok_or(AiffError::MissingComm)?;
+        // the stream may have repaired it
+        comm.num_sample_frames = stream.comm().num_sample_frames;
+
         Ok(Self {
-            comm: comm.ok_or(AiffError::MissingComm)?,
+            form_type,
+            fver,
+            comm,
             ssnd: ssnd.ok_or(AiffError::MissingSsnd)?,
             mark,
             inst,
-            other_chunks,
+            vadpcm_codes,
+            vadpcm_loops,
+            midi,
+            aesd,
+            appl,
+            comt,
+            name,
+            author,
+            copyright,
+            annotations,
+            chunks,
+            warnings: stream.warnings().to_vec(),
         })
     }
 
-    pub fn samples(&self) -> Samples<'a> {
-        Samples {
-            data: self.ssnd.raw_data(),
-            remaining_sample_frames: self.comm.num_sample_frames,
-            sample_size: self.comm.sample_size,
+    /// Copies the sound data and raw chunks if they're borrowed.
+    pub fn into_owned(self) -> OwnedAiff {
+        Aiff {
+            ssnd: self.ssnd.into_owned(),
+            chunks: self.chunks.into_iter().map(RawChunk::into_owned).collect(),
+            form_type: self.form_type,
+            fver: self.fver,
+            comm: self.comm,
+            mark: self.mark,
+            inst: self.inst,
+            vadpcm_codes: self.vadpcm_codes,
+            vadpcm_loops: self.vadpcm_loops,
+            midi: self.midi,
+            aesd: self.aesd,
+            appl: self.appl,
+            comt: self.comt,
+            name: self.name,
+            author: self.author,
+            copyright: self.copyright,
+            annotations: self.annotations,
+            warnings: self.warnings,
         }
     }
-}
 
-#[derive(Debug)]
-pub struct Samples<'a> {
-    data: &'a [u8],
-    remaining_sample_frames: u32,
-    sample_size: u16,
-}
+    /// A copy of the file that borrows its sound data and raw chunks from this one.
+    pub fn to_borrowed(&self) -> Aiff<'_> {
+        let mut ssnd = ssnd::SoundDataChunk::new(self.ssnd.raw_data());
+        ssnd.offset = self.ssnd.offset;
+        ssnd.block_size = self.ssnd.block_size;
+        let chunks = self
+            .chunks
+            .iter()
+            .map(|chunk| RawChunk {
+                id: chunk.id,
+                offset: chunk.offset,
+                data: Cow::Borrowed(&chunk.data),
+                kind: chunk.kind,
+            })
+            .collect();
 
-impl<'a> Iterator for Samples<'a> {
-    type Item = i32;
+        Aiff {
+            ssnd,
+            chunks,
+            form_type: self.form_type,
+            fver: self.fver,
+            comm: self.comm.clone(),
+            mark: self.mark.clone(),
+            inst: self.inst.clone(),
+            vadpcm_codes: self.vadpcm_codes.clone(),
+            vadpcm_loops: self.vadpcm_loops.clone(),
+            midi: self.midi.clone(),
+            aesd: self.aesd.clone(),
+            appl: self.appl.clone(),
+            comt: self.comt.clone(),
+            name: self.name.clone(),
+            author: self.author.clone(),
+            copyright: self.copyright.clone(),
+            annotations: self.annotations.clone(),
+            warnings: self.warnings.clone(),
+        }
+    }
 
-    fn next(&mut self) -> Option<i32> {
-        let bytes_per_sample = (self.sample_size + 7) / 8;
+    /// The marker a comment is about, if any.
+    pub fn comment_marker(&self, comment: &comt::Comment) -> Option<&mark::Marker> {
+        self.mark.as_ref()?.marker(comment.marker?)
+    }
 
-        if self.data.len() < bytes_per_sample as usize || self.remaining_sample_frames == 0 {
-            None
-        } else {
-            self.remaining_sample_frames -= 1;
+    /// Chunks not parsed into anything.
+    pub fn other_chunks(&self) -> impl Iterator<Item = &RawChunk<'a>> {
+        self.chunks
+            .iter()
+            .filter(|chunk| chunk.kind == ChunkKind::Other)
+    }
 
-            let value = match bytes_per_sample {
-                1 => {
-                    let v = self.data[0] as i8;
-                    (v >> (8 - self.sample_size)) as i32
-                }
+    /// Every parsed chunk present, in the order they're written in when they don't come from
+    /// `chunks`.
+    fn chunk_kinds(&self) -> Vec<ChunkKind> {
+        let mut kinds = Vec::new();
+        if self.fver.is_some() {
+            kinds.push(ChunkKind::Fver);
+        }
+        kinds.push(ChunkKind::Comm);
+        if self.mark.is_some() {
+            kinds.push(ChunkKind::Mark);
+        }
+        if self.inst.is_some() {
+            kinds.push(ChunkKind::Inst);
+        }
+        kinds.extend((0..self.midi.len()).map(ChunkKind::Midi));
+        if self.aesd.is_some() {
+            kinds.push(ChunkKind::Aesd);
+        }
+        if self.vadpcm_codes.is_some() {
+            kinds.push(ChunkKind::VadpcmCodes);
+        }
+        if self.vadpcm_loops.is_some() {
+            kinds.push(ChunkKind::VadpcmLoops);
+        }
+        kinds.extend((0..self.appl.len()).map(ChunkKind::Appl));
+        if self.comt.is_some() {
+            kinds.push(ChunkKind::Comt);
+        }
+        if self.name.is_some() {
+            kinds.push(ChunkKind::Name);
+        }
+        if self.author.is_some() {
+            kinds.push(ChunkKind::Author);
+        }
+        if self.copyright.is_some() {
+            kinds.push(ChunkKind::Copyright);
+        }
+        kinds.extend((0..self.annotations.len()).map(ChunkKind::Annotation));
+        kinds.push(ChunkKind::Ssnd);
+        kinds
+    }
 
-                2 => {
-                    let v = BE::read_i16(self.data);
-                    (v >> (16 - self.sample_size)) as i32
-                }
+    /// Encodes a parsed chunk, which must be present.
+    fn encode(&self, kind: ChunkKind) -> Result<(ID, Vec<u8>)> {
+        match kind {
+            ChunkKind::Fver => encode_chunk(b"FVER", |w| self.fver.unwrap().write(w)),
+            ChunkKind::Comm => encode_chunk(b"COMM", |w| self.comm.write(w, self.form_type)),
+            ChunkKind::Ssnd => encode_chunk(b"SSND", |w| self.ssnd.write(w)),
+            ChunkKind::Mark => encode_chunk(b"MARK", |w| self.mark.as_ref().unwrap().write(w)),
+            ChunkKind::Inst => encode_chunk(b"INST", |w| self.inst.as_ref().unwrap().write(w)),
+            ChunkKind::VadpcmCodes => {
+                encode_chunk(b"APPL", |w| self.vadpcm_codes.as_ref().unwrap().write(w))
+            }
+            ChunkKind::VadpcmLoops => {
+                encode_chunk(b"APPL", |w| self.vadpcm_loops.as_ref().unwrap().write(w))
+            }
+            ChunkKind::Midi(i) => encode_chunk(b"MIDI", |w| self.midi[i].write(w)),
+            ChunkKind::Aesd => encode_chunk(b"AESD", |w| self.aesd.as_ref().unwrap().write(w)),
+            ChunkKind::Appl(i) => encode_chunk(b"APPL", |w| self.appl[i].write(w)),
+            ChunkKind::Comt => encode_chunk(b"COMT", |w| self.comt.as_ref().unwrap().write(w)),
+            ChunkKind::Name => encode_chunk(b"NAME", |w| self.name.as_ref().unwrap().write(w)),
+            ChunkKind::Author => encode_chunk(b"AUTH", |w| self.author.as_ref().unwrap().write(w)),
+            ChunkKind::Copyright => {
+                encode_chunk(b"(c) ", |w| self.copyright.as_ref().unwrap().write(w))
+            }
+            ChunkKind::Annotation(i) => encode_chunk(b"ANNO", |w| self.annotations[i].write(w)),
+            ChunkKind::Other => unreachable!("other chunks aren't parsed"),
+        }
+    }
 
-                3 => {
-                    let v = i32::from_be_bytes([0, self.data[0], self.data[1], self.data[2]]);
-                    v >> (24 - self.sample_size)
-                }
+    /// Whether a parsed chunk, which must be present, is still what the raw chunk holds.
+    fn is_unchanged(&self, chunk: &RawChunk) -> Result<bool> {
+        let data = &chunk.data[..];
+        Ok(match chunk.kind {
+            ChunkKind::Fver => self.fver == Some(fver::FormatVersionChunk::read(data)?),
+            ChunkKind::Comm => self.comm == comm::CommonChunk::read(data, self.form_type)?,
+            ChunkKind::Ssnd => self.ssnd == ssnd::SoundDataChunk::read(data)?,
+            ChunkKind::Mark => self.mark == Some(mark::MarkerChunk::read(data)?),
+            ChunkKind::Inst => self.inst == Some(inst::InstrumentChunk::read(data)?),
+            ChunkKind::VadpcmCodes => {
+                let (_, _, data) = vadpcm::read_appl_header(data)?;
+                self.vadpcm_codes == Some(vadpcm::VadpcmCodesChunk::read(data)?)
+            }
+            ChunkKind::VadpcmLoops => {
+                let (_, _, data) = vadpcm::read_appl_header(data)?;
+                self.vadpcm_loops == Some(vadpcm::VadpcmLoopsChunk::read(data)?)
+            }
+            ChunkKind::Midi(i) => self.midi[i] == midi::MidiDataChunk::read(data)?,
+            ChunkKind::Aesd => self.aesd == Some(aesd::AudioRecordingChunk::read(data)?),
+            ChunkKind::Appl(i) => self.appl[i] == appl::ApplicationSpecificChunk::read(data)?,
+            ChunkKind::Comt => self.comt == Some(comt::CommentsChunk::read(data)?),
+            ChunkKind::Name => self.name == Some(text::TextChunk::read(data)?),
+            ChunkKind::Author => self.author == Some(text::TextChunk::read(data)?),
+            ChunkKind::Copyright => self.copyright == Some(text::TextChunk::read(data)?),
+            ChunkKind::Annotation(i) => self.annotations[i] == text::TextChunk::read(data)?,
+            ChunkKind::Other => true,
+        })
+    }
+
+    /// Writes the file. Chunks from `chunks` are written in order, as they were unless their
+    /// parsed version was modified, and parsed chunks that were removed are skipped. New
+    /// parsed chunks are then written before the sound data chunk.
+    pub fn write(&self, w: &mut impl Write) -> Result<()> {
+        let kinds = self.chunk_kinds();
+        let mut written = Vec::new();
+        let mut chunks: Vec<(ID, Cow<[u8]>)> = Vec::new();
 
-                4 => {
-                    let v = BE::read_i32(self.data);
-                    v >> (32 - self.sample_size)
+        for chunk in &self.chunks {
+            if chunk.kind != ChunkKind::Other {
+                if !kinds.contains(&chunk.kind) || written.contains(&chunk.kind) {
+                    continue;
                 }
+                written.push(chunk.kind);
+            }
 
-                _ => panic!("invalid sample size {}", self.sample_size),
-            };
-            self.data = &self.data[bytes_per_sample as usize..];
-            Some(value)
+            if self.is_unchanged(chunk)? {
+                chunks.push((chunk.id, Cow::Borrowed(&chunk.data)));
+            } else {
+                let (id, data) = self.encode(chunk.kind)?;
+                chunks.push((id, Cow::Owned(data)));
+            }
         }
+
+        for kind in kinds {
+            if written.contains(&kind) {
+                continue;
+            }
+            let (id, data) = self.encode(kind)?;
+            let position = chunks
+                .iter()
+                .position(|(id, _)| id.data() == b"SSND")
+                .unwrap_or(chunks.len());
+            chunks.insert(position, (id, Cow::Owned(data)));
+        }
+
+        let form_size = 4 + chunks
+            .iter()
+            .map(|(_, data)| 8 + data.len() + data.len() % 2)
+            .sum::<usize>();
+        w.write_all(b"FORM")?;
+        w.write_all(&(form_size as u32).to_be_bytes())?;
+        w.write_all(self.form_type.id().data())?;
+        for (id, data) in chunks {
+            write_chunk(w, id, &data)?;
+        }
+        Ok(())
+    }
+
+    /// The instrument's sustain loop, resolved against the markers.
+    pub fn sustain_loop(&self) -> Option<inst::LoopRange> {
+        self.inst
+            .as_ref()?
+            .sustain_loop
+            .resolve(self.mark.as_ref()?)
+    }
+
+    /// The instrument's release loop, resolved against the markers.
+    pub fn release_loop(&self) -> Option<inst::LoopRange> {
+        self.inst
+            .as_ref()?
+            .release_loop
+            .resolve(self.mark.as_ref()?)
+    }
+
+    /// Iterates over the samples, decoding them if they're compressed with VADPCM. Loops are
+    /// not followed; see [`Frames::looped`].
+    pub fn samples(&self) -> Result<Samples<'_>> {
+        Samples::new(&self.comm, self.vadpcm_codes.as_ref(), self.ssnd.raw_data())
     }
 }
 
-impl<'a> Samples<'a> {
-    pub fn normalize_to_f32(self) -> impl Iterator<Item = f32> + 'a {
-        let sample_size = self.sample_size;
-        self.map(move |s| s as f32 / ((1 << sample_size) - 1) as f32)
+/// Writes a chunk's data into a buffer, as its size is needed before writing it out.
+fn encode_chunk(
+    id: &[u8; 4],
+    write: impl FnOnce(&mut Vec<u8>) -> Result<()>,
+) -> Result<(ID, Vec<u8>)> {
+    let mut data = Vec::new();
+    write(&mut data)?;
+    Ok((ID::new(*id), data))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn aifc() -> Aiff<'static> {
+        let comm = comm::CommonChunk {
+            num_channels: 1,
+            num_sample_frames: 2,
+            sample_size: 16,
+            sample_rate: 8000.0,
+            compression: Some(comm::Compression::none()),
+        };
+        Aiff::new(FormType::Aifc, comm, ssnd::SoundDataChunk::new(vec![0; 4]))
+    }
+
+    fn chunk_ids(file: &Aiff) -> Vec<ID> {
+        let mut data = Vec::new();
+        file.write(&mut data).unwrap();
+        let file = AiffReader::all().read(&data).unwrap();
+        file.chunks.iter().map(|chunk| chunk.id).collect()
+    }
+
+    #[test]
+    fn format_version_only_when_set() {
+        let mut file = aifc();
+        assert_eq!(chunk_ids(&file), [ID::new(*b"COMM"), ID::new(*b"SSND")]);
+
+        file.fver = Some(fver::FormatVersionChunk::default());
+        assert_eq!(
+            chunk_ids(&file),
+            [ID::new(*b"FVER"), ID::new(*b"COMM"), ID::new(*b"SSND")]
+        );
+    }
+
+    fn marks(name: &str) -> mark::MarkerChunk {
+        mark::MarkerChunk {
+            markers: vec![mark::Marker {
+                id: 1,
+                position: 1,
+                name: name.to_string(),
+            }],
+        }
+    }
+
+    #[test]
+    fn duplicate_chunks_round_trip() {
+        let file = aifc();
+        let chunks = [
+            encode_chunk(b"COMM", |w| file.comm.write(w, FormType::Aifc)).unwrap(),
+            encode_chunk(b"MARK", |w| marks("first").write(w)).unwrap(),
+            encode_chunk(b"MARK", |w| marks("second").write(w)).unwrap(),
+            encode_chunk(b"SSND", |w| file.ssnd.write(w)).unwrap(),
+        ];
+        let mut body = b"AIFC".to_vec();
+        for (id, chunk) in &chunks {
+            write_chunk(&mut body, *id, chunk).unwrap();
+        }
+        let mut data = b"FORM".to_vec();
+        data.extend_from_slice(&(body.len() as u32).to_be_bytes());
+        data.extend(body);
+
+        // only the last marker chunk is parsed, the first one is kept as is
+        let mut aiff = AiffReader::all().read(&data).unwrap();
+        assert_eq!(aiff.mark, Some(marks("second")));
+        let kinds: Vec<_> = aiff.chunks.iter().map(|chunk| chunk.kind).collect();
+        assert_eq!(
+            kinds,
+            [
+                ChunkKind::Comm,
+                ChunkKind::Other,
+                ChunkKind::Mark,
+                ChunkKind::Ssnd
+            ]
+        );
+
+        let mut written = Vec::new();
+        aiff.write(&mut written).unwrap();
+        assert_eq!(written, data);
+
+        // modifying the parsed one only replaces the last chunk
+        aiff.mark = Some(marks("third"));
+        let mut written = Vec::new();
+        aiff.write(&mut written).unwrap();
+        let aiff = AiffReader::all().read(&written).unwrap();
+        let ids: Vec<_> = aiff.chunks.iter().map(|chunk| chunk.id).collect();
+        assert_eq!(
+            ids,
+            [
+                ID::new(*b"COMM"),
+                ID::new(*b"MARK"),
+                ID::new(*b"MARK"),
+                ID::new(*b"SSND")
+            ]
+        );
+        assert_eq!(
+            mark::MarkerChunk::read(&aiff.chunks[1].data).unwrap(),
+            marks("first")
+        );
+        assert_eq!(aiff.mark, Some(marks("third")));
     }
 }
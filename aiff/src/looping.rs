@@ -0,0 +1,95 @@
+use crate::chunks::inst::{LoopRange, PlayMode};
+
+/// An iterator over sample frames that follows an instrument's loops, the way a sampler would:
+/// the sustain loop plays until the note is released, then playback goes on and the release
+/// loop plays forever, if there's one.
+#[derive(Debug, Clone)]
+pub struct LoopedFrames<S> {
+    frames: Vec<Vec<S>>,
+    sustain_loop: Option<LoopRange>,
+    release_loop: Option<LoopRange>,
+    position: usize,
+    backward: bool,
+    released: bool,
+}
+
+impl<S: Clone> LoopedFrames<S> {
+    /// Loops past the end of the frames are ignored.
+    pub fn new(
+        frames: Vec<Vec<S>>,
+        sustain_loop: Option<LoopRange>,
+        release_loop: Option<LoopRange>,
+    ) -> Self {
+        let fits = |range: &LoopRange| range.end as usize <= frames.len();
+        let sustain_loop = sustain_loop.filter(fits);
+        let release_loop = release_loop.filter(fits);
+
+        Self {
+            frames,
+            sustain_loop,
+            release_loop,
+            position: 0,
+            backward: false,
+            released: false,
+        }
+    }
+
+    /// Releases the note, leaving the sustain loop.
+    pub fn release(&mut self) {
+        self.released = true;
+        self.backward = false;
+    }
+
+    pub fn is_released(&self) -> bool {
+        self.released
+    }
+
+    /// The loop playback is in, or will be in once it reaches its end.
+    fn current_loop(&self) -> Option<LoopRange> {
+        if self.released {
+            self.release_loop
+        } else {
+            self.sustain_loop
+        }
+    }
+
+    fn advance(&mut self) {
+        let range = self.current_loop();
+
+        if self.backward {
+            // only ever set within a forward/backward loop
+            let start = range.map_or(0, |range| range.start as usize);
+            if self.position <= start {
+                self.backward = false;
+                self.position = start + 1;
+            } else {
+                self.position -= 1;
+            }
+            return;
+        }
+
+        self.position += 1;
+        if let Some(range) = range {
+            let (start, end) = (range.start as usize, range.end as usize);
+            if self.position == end {
+                match range.play_mode {
+                    PlayMode::ForwardBackwardLooping if end - start > 1 => {
+                        self.backward = true;
+                        self.position = end - 2;
+                    }
+                    _ => self.position = start,
+                }
+            }
+        }
+    }
+}
+
+impl<S: Clone> Iterator for LoopedFrames<S> {
+    type Item = Vec<S>;
+
+    fn next(&mut self) -> Option<Vec<S>> {
+        let frame = self.frames.get(self.position)?.clone();
+        self.advance();
+        Some(frame)
+    }
+}
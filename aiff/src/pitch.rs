@@ -0,0 +1,55 @@
+use crate::chunks::inst::NoteSettings;
+
+/// An iterator over sample frames played at another pitch and amplitude, interpolating linearly
+/// between them.
+#[derive(Debug, Clone)]
+pub struct Pitched<I> {
+    frames: I,
+    pitch_ratio: f64,
+    amplitude: f32,
+    /// Position between `current` and `next`, from 0 to 1.
+    position: f64,
+    current: Option<Vec<f32>>,
+    next: Option<Vec<f32>>,
+}
+
+impl<I: Iterator<Item = Vec<f32>>> Pitched<I> {
+    pub fn new(mut frames: I, settings: NoteSettings) -> Self {
+        let current = frames.next();
+        let next = frames.next();
+
+        Self {
+            frames,
+            pitch_ratio: settings.pitch_ratio,
+            amplitude: settings.amplitude,
+            position: 0.0,
+            current,
+            next,
+        }
+    }
+}
+
+impl<I: Iterator<Item = Vec<f32>>> Iterator for Pitched<I> {
+    type Item = Vec<f32>;
+
+    fn next(&mut self) -> Option<Vec<f32>> {
+        let current = self.current.as_ref()?;
+        let t = self.position as f32;
+        let frame = match &self.next {
+            Some(next) => current
+                .iter()
+                .zip(next)
+                .map(|(a, b)| (a + (b - a) * t) * self.amplitude)
+                .collect(),
+            None => current.iter().map(|a| a * self.amplitude).collect(),
+        };
+
+        self.position += self.pitch_ratio;
+        while self.position >= 1.0 && self.current.is_some() {
+            self.position -= 1.0;
+            self.current = self.next.take();
+            self.next = self.frames.next();
+        }
+        Some(frame)
+    }
+}
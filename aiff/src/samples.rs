@@ -0,0 +1,542 @@
+use crate::chunks::comm::CommonChunk;
+use crate::chunks::inst::LoopRange;
+use crate::chunks::vadpcm::VadpcmCodesChunk;
+use crate::codecs;
+use crate::error::*;
+use crate::looping::LoopedFrames;
+use byteorder::{ByteOrder, BE};
+use std::marker::PhantomData;
+use vadpcm::{Codebook, Decoder, FrameFormat, SAMPLES_PER_FRAME};
+
+/// How the sound data is stored.
+#[derive(Debug, Clone)]
+pub(crate) enum Encoding {
+    /// Samples or packets that can be decoded on their own.
+    Format(SampleFormat),
+    Vadpcm(Codebook),
+}
+
+/// Sound data made of units that can be decoded independently of each other.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum SampleFormat {
+    Pcm { little_endian: bool },
+    Float32,
+    Float64,
+    ULaw,
+    ALaw,
+    Ima4,
+}
+
+impl Encoding {
+    pub(crate) fn new(comm: &CommonChunk, codes: Option<&VadpcmCodesChunk>) -> Result<Self> {
+        match SampleFormat::new(comm)? {
+            Some(format) => Ok(Encoding::Format(format)),
+            None => {
+                let codes = codes.ok_or(AiffError::MissingVadpcmCodes)?;
+                Ok(Encoding::Vadpcm(codes.book.clone()))
+            }
+        }
+    }
+
+    /// Size of the samples once decoded.
+    pub(crate) fn sample_size(&self, comm: &CommonChunk) -> u16 {
+        match self {
+            Encoding::Format(SampleFormat::Pcm { .. }) => comm.sample_size,
+            Encoding::Format(SampleFormat::Float32) | Encoding::Format(SampleFormat::Float64) => 32,
+            _ => 16,
+        }
+    }
+
+    /// Amount of samples across all channels.
+    pub(crate) fn num_samples(&self, comm: &CommonChunk) -> u64 {
+        let num_samples = comm.num_sample_frames as u64 * comm.num_channels as u64;
+        match self {
+            // the frame count is in packets
+            Encoding::Format(SampleFormat::Ima4) => {
+                num_samples * codecs::IMA4_SAMPLES_PER_PACKET as u64
+            }
+            _ => num_samples,
+        }
+    }
+}
+
+impl SampleFormat {
+    /// The format of the sound data, `None` meaning it's VADPCM-compressed.
+    pub(crate) fn new(comm: &CommonChunk) -> Result<Option<Self>> {
+        let id = comm.compression_type();
+        // some of these are found in both cases
+        let format = match &id.data().to_ascii_lowercase()[..] {
+            b"none" | b"twos" => SampleFormat::Pcm {
+                little_endian: false,
+            },
+            b"sowt" => SampleFormat::Pcm {
+                little_endian: true,
+            },
+            b"fl32" => SampleFormat::Float32,
+            b"fl64" => SampleFormat::Float64,
+            b"ulaw" => SampleFormat::ULaw,
+            b"alaw" => SampleFormat::ALaw,
+            b"ima4" => SampleFormat::Ima4,
+            b"vapc" => return Ok(None),
+            _ => return Err(AiffError::UnsupportedCompression(id)),
+        };
+        if let SampleFormat::Pcm { .. } = format {
+            if !(1..=32).contains(&comm.sample_size) {
+                return Err(AiffError::InvalidSampleSize(comm.sample_size));
+            }
+        }
+        Ok(Some(format))
+    }
+
+    /// Size of the units the data is decoded by.
+    pub(crate) fn unit_size(self, sample_size: u16, num_channels: u16) -> usize {
+        match self {
+            SampleFormat::Pcm { .. } => sample_size.div_ceil(8) as usize,
+            SampleFormat::Float32 => 4,
+            SampleFormat::Float64 => 8,
+            SampleFormat::ULaw | SampleFormat::ALaw => 1,
+            SampleFormat::Ima4 => codecs::IMA4_PACKET_SIZE * num_channels as usize,
+        }
+    }
+
+    /// Decodes a unit, which must be whole.
+    pub(crate) fn decode_unit(
+        self,
+        data: &[u8],
+        sample_size: u16,
+        num_channels: u16,
+        out: &mut Vec<i32>,
+    ) {
+        match self {
+            SampleFormat::Pcm { little_endian } => {
+                out.push(read_pcm_sample(data, sample_size, little_endian))
+            }
+            SampleFormat::Float32 => out.push(codecs::decode_float32(data)),
+            SampleFormat::Float64 => out.push(codecs::decode_float64(data)),
+            SampleFormat::ULaw => out.push(codecs::decode_ulaw(data[0])),
+            SampleFormat::ALaw => out.push(codecs::decode_alaw(data[0])),
+            SampleFormat::Ima4 => codecs::decode_ima4(data, num_channels as usize, out),
+        }
+    }
+}
+
+/// How many sample frames sound data of the given size holds, counted as in the common chunk,
+/// if the format is supported.
+pub(crate) fn sample_frame_capacity(comm: &CommonChunk, data_len: u64) -> Option<u64> {
+    if comm.num_channels == 0 {
+        return None;
+    }
+    let num_channels = comm.num_channels as u64;
+    match SampleFormat::new(comm).ok()? {
+        // IMA ADPCM frames are counted in packets
+        Some(SampleFormat::Ima4) => {
+            Some(data_len / (codecs::IMA4_PACKET_SIZE as u64 * num_channels))
+        }
+        Some(format) => {
+            let unit_size = format.unit_size(comm.sample_size, comm.num_channels) as u64;
+            Some(data_len / unit_size / num_channels)
+        }
+        None => {
+            let frames = data_len / FrameFormat::Bits4.frame_size() as u64;
+            Some(frames * SAMPLES_PER_FRAME as u64 / num_channels)
+        }
+    }
+}
+
+/// A source of triangular probability density function dither, added to samples before
+/// reducing their bit depth so that the rounding error becomes noise rather than distortion.
+#[derive(Debug, Clone)]
+pub struct Dither {
+    state: u32,
+}
+
+impl Dither {
+    pub fn new(seed: u32) -> Self {
+        // xorshift never leaves 0
+        Self {
+            state: if seed == 0 { 0x9e37_79b9 } else { seed },
+        }
+    }
+
+    fn next_u32(&mut self) -> u32 {
+        let mut x = self.state;
+        x ^= x << 13;
+        x ^= x >> 17;
+        x ^= x << 5;
+        self.state = x;
+        x
+    }
+
+    /// Noise between -1 and 1 LSB of a sample `shift` bits shorter, in units of the original
+    /// sample.
+    fn tpdf(&mut self, shift: u16) -> i64 {
+        let uniform = |x: u32| (x as u64 >> (32 - shift)) as i64;
+        uniform(self.next_u32()) - uniform(self.next_u32())
+    }
+}
+
+impl Default for Dither {
+    fn default() -> Self {
+        Self::new(1)
+    }
+}
+
+/// Panics unless the bit depth is one samples can have.
+pub(crate) fn check_sample_size(sample_size: u16) {
+    assert!(
+        (1..=32).contains(&sample_size),
+        "sample size must be from 1 to 32 bits, not {}",
+        sample_size
+    );
+}
+
+/// Converts a sample of `from_bits` bits to `to_bits` bits. When reducing the bit depth, the
+/// sample is rounded to the nearest value, after adding dither if any, and clamped.
+///
+/// Panics if either bit depth isn't from 1 to 32.
+pub fn requantize(value: i32, from_bits: u16, to_bits: u16, dither: Option<&mut Dither>) -> i32 {
+    check_sample_size(from_bits);
+    check_sample_size(to_bits);
+    if to_bits >= from_bits {
+        return ((value as i64) << (to_bits - from_bits)) as i32;
+    }
+
+    let shift = from_bits - to_bits;
+    let mut value = value as i64;
+    if let Some(dither) = dither {
+        value += dither.tpdf(shift);
+    }
+    let rounded = (value + (1 << (shift - 1))) >> shift;
+    let max = (1i64 << (to_bits - 1)) - 1;
+    rounded.clamp(-max - 1, max) as i32
+}
+
+/// A type samples can be converted to, mapping the full scale of the file's samples to its own.
+pub trait Sample: Copy {
+    /// Converts a sample of `sample_size` bits, as read from the file. Panics if the size isn't
+    /// from 1 to 32 bits.
+    fn from_raw(value: i32, sample_size: u16) -> Self;
+    /// Converts a sample of `sample_size` bits, dithering it if its bit depth gets reduced.
+    fn from_raw_dithered(value: i32, sample_size: u16, _dither: &mut Dither) -> Self {
+        Self::from_raw(value, sample_size)
+    }
+    /// Averages the samples of a frame, for downmixing.
+    fn average(samples: &[Self]) -> Self;
+}
+
+impl Sample for i16 {
+    fn from_raw(value: i32, sample_size: u16) -> Self {
+        requantize(value, sample_size, 16, None) as i16
+    }
+
+    fn from_raw_dithered(value: i32, sample_size: u16, dither: &mut Dither) -> Self {
+        requantize(value, sample_size, 16, Some(dither)) as i16
+    }
+
+    fn average(samples: &[Self]) -> Self {
+        (samples.iter().map(|&s| s as i32).sum::<i32>() / samples.len() as i32) as i16
+    }
+}
+
+impl Sample for i32 {
+    fn from_raw(value: i32, sample_size: u16) -> Self {
+        requantize(value, sample_size, 32, None)
+    }
+
+    fn average(samples: &[Self]) -> Self {
+        (samples.iter().map(|&s| s as i64).sum::<i64>() / samples.len() as i64) as i32
+    }
+}
+
+impl Sample for f32 {
+    fn from_raw(value: i32, sample_size: u16) -> Self {
+        f64::from_raw(value, sample_size) as f32
+    }
+
+    fn average(samples: &[Self]) -> Self {
+        samples.iter().sum::<f32>() / samples.len() as f32
+    }
+}
+
+impl Sample for f64 {
+    fn from_raw(value: i32, sample_size: u16) -> Self {
+        check_sample_size(sample_size);
+        value as f64 / (1u64 << (sample_size - 1)) as f64
+    }
+
+    fn average(samples: &[Self]) -> Self {
+        samples.iter().sum::<f64>() / samples.len() as f64
+    }
+}
+
+/// Converts a sample, dithering it if there's dither.
+pub(crate) fn convert_sample<S: Sample>(
+    value: i32,
+    sample_size: u16,
+    dither: Option<&mut Dither>,
+) -> S {
+    match dither {
+        Some(dither) => S::from_raw_dithered(value, sample_size, dither),
+        None => S::from_raw(value, sample_size),
+    }
+}
+
+/// Reads an uncompressed sample of `sample_size` bits, from 1 to 32, out of as many bytes as it
+/// takes.
+pub(crate) fn read_pcm_sample(data: &[u8], sample_size: u16, little_endian: bool) -> i32 {
+    let bytes_per_sample = sample_size.div_ceil(8) as usize;
+
+    let mut bytes = [0; 4];
+    bytes[..bytes_per_sample].copy_from_slice(&data[..bytes_per_sample]);
+    if little_endian {
+        bytes[..bytes_per_sample].reverse();
+    }
+
+    match bytes_per_sample {
+        1 => {
+            let v = bytes[0] as i8;
+            (v >> (8 - sample_size)) as i32
+        }
+
+        2 => {
+            let v = BE::read_i16(&bytes);
+            (v >> (16 - sample_size)) as i32
+        }
+
+        3 => {
+            let v = i32::from_be_bytes([bytes[0], bytes[1], bytes[2], 0]);
+            v >> (32 - sample_size)
+        }
+
+        _ => {
+            let v = BE::read_i32(&bytes);
+            v >> (32 - sample_size)
+        }
+    }
+}
+
+/// An iterator over interleaved samples, as stored in the file.
+#[derive(Debug)]
+pub struct Samples<'a> {
+    source: Source<'a>,
+    /// Samples left across all channels.
+    remaining_samples: u64,
+    num_channels: u16,
+    sample_size: u16,
+    dither: Option<Dither>,
+}
+
+#[derive(Debug)]
+enum Source<'a> {
+    Format {
+        data: &'a [u8],
+        format: SampleFormat,
+        /// The samples decoded out of the last unit.
+        unit: Vec<i32>,
+        position: usize,
+    },
+    Vadpcm(vadpcm::Samples<'a>),
+}
+
+impl<'a> Samples<'a> {
+    pub(crate) fn new(
+        comm: &CommonChunk,
+        codes: Option<&VadpcmCodesChunk>,
+        data: &'a [u8],
+    ) -> Result<Self> {
+        let encoding = Encoding::new(comm, codes)?;
+        let sample_size = encoding.sample_size(comm);
+        let remaining_samples = encoding.num_samples(comm);
+        let source = match encoding {
+            Encoding::Format(format) => Source::Format {
+                data,
+                format,
+                unit: Vec::new(),
+                position: 0,
+            },
+            Encoding::Vadpcm(book) => {
+                Source::Vadpcm(Decoder::new(book, FrameFormat::Bits4).samples(data)?)
+            }
+        };
+
+        Ok(Self {
+            source,
+            remaining_samples,
+            num_channels: comm.num_channels,
+            sample_size,
+            dither: None,
+        })
+    }
+
+    /// Groups the samples into frames holding one sample per channel.
+    pub fn frames<S: Sample>(self) -> Frames<'a, S> {
+        Frames {
+            samples: self,
+            _sample: PhantomData,
+        }
+    }
+
+    /// Dithers the samples whenever their bit depth gets reduced.
+    pub fn with_dither(mut self, dither: Dither) -> Self {
+        self.dither = Some(dither);
+        self
+    }
+
+    /// Converts the samples, without grouping them into frames.
+    pub fn convert<S: Sample>(mut self) -> impl Iterator<Item = S> + 'a {
+        let sample_size = self.sample_size;
+        let mut dither = self.dither.take();
+        self.map(move |value| convert_sample(value, sample_size, dither.as_mut()))
+    }
+
+    /// Converts the samples to floats from -1 to 1.
+    pub fn normalize_to_f32(self) -> impl Iterator<Item = f32> + 'a {
+        self.convert()
+    }
+
+    /// Requantizes the samples to `sample_size` bits. Panics if it isn't from 1 to 32.
+    pub fn requantize(mut self, sample_size: u16) -> impl Iterator<Item = i32> + 'a {
+        check_sample_size(sample_size);
+        let from_bits = self.sample_size;
+        let mut dither = self.dither.take();
+        self.map(move |value| requantize(value, from_bits, sample_size, dither.as_mut()))
+    }
+}
+
+impl<'a> Iterator for Samples<'a> {
+    type Item = i32;
+
+    fn next(&mut self) -> Option<i32> {
+        if self.remaining_samples == 0 {
+            return None;
+        }
+
+        let value = match &mut self.source {
+            Source::Format {
+                data,
+                format,
+                unit,
+                position,
+            } => {
+                if *position == unit.len() {
+                    let unit_size = format.unit_size(self.sample_size, self.num_channels);
+                    if data.len() < unit_size {
+                        return None;
+                    }
+
+                    unit.clear();
+                    *position = 0;
+                    format.decode_unit(
+                        &data[..unit_size],
+                        self.sample_size,
+                        self.num_channels,
+                        unit,
+                    );
+                    *data = &data[unit_size..];
+                }
+
+                *position += 1;
+                unit[*position - 1]
+            }
+            Source::Vadpcm(samples) => samples.next()? as i32,
+        };
+
+        self.remaining_samples -= 1;
+        Some(value)
+    }
+}
+
+/// An iterator over sample frames, each holding one sample per channel.
+#[derive(Debug)]
+pub struct Frames<'a, S> {
+    samples: Samples<'a>,
+    _sample: PhantomData<S>,
+}
+
+impl<'a, S: Sample> Frames<'a, S> {
+    /// Splits the samples by channel.
+    pub fn deinterleave(self) -> Vec<Vec<S>> {
+        let mut channels = vec![Vec::new(); self.samples.num_channels as usize];
+        for frame in self {
+            for (channel, sample) in channels.iter_mut().zip(frame) {
+                channel.push(sample);
+            }
+        }
+        channels
+    }
+
+    /// Mixes every channel down to one.
+    pub fn downmix(self) -> impl Iterator<Item = S> + 'a
+    where
+        S: 'a,
+    {
+        self.map(|frame| S::average(&frame))
+    }
+
+    /// Reads every frame, then plays them following the given loops.
+    pub fn looped(
+        self,
+        sustain_loop: Option<LoopRange>,
+        release_loop: Option<LoopRange>,
+    ) -> LoopedFrames<S> {
+        LoopedFrames::new(self.collect(), sustain_loop, release_loop)
+    }
+}
+
+impl<'a, S: Sample> Iterator for Frames<'a, S> {
+    type Item = Vec<S>;
+
+    fn next(&mut self) -> Option<Vec<S>> {
+        let num_channels = self.samples.num_channels as usize;
+        let sample_size = self.samples.sample_size;
+        let mut frame = Vec::with_capacity(num_channels);
+        for _ in 0..num_channels {
+            let value = self.samples.next()?;
+            frame.push(convert_sample(
+                value,
+                sample_size,
+                self.samples.dither.as_mut(),
+            ));
+        }
+
+        if frame.is_empty() {
+            None
+        } else {
+            Some(frame)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn requantize_extremes() {
+        assert_eq!(requantize(-1, 1, 32, None), i32::MIN);
+        assert_eq!(requantize(0, 1, 16, None), 0);
+        assert_eq!(requantize(i32::MAX, 32, 1, None), 0);
+        assert_eq!(requantize(i32::MIN, 32, 1, None), -1);
+        assert_eq!(requantize(i32::MAX, 32, 16, None), i16::MAX as i32);
+        assert_eq!(f64::from_raw(-1, 1), -1.0);
+        assert_eq!(f64::from_raw(i32::MIN, 32), -1.0);
+    }
+
+    #[test]
+    #[should_panic(expected = "sample size must be from 1 to 32 bits, not 0")]
+    fn requantize_to_zero_bits() {
+        requantize(0, 16, 0, None);
+    }
+
+    #[test]
+    #[should_panic(expected = "sample size must be from 1 to 32 bits, not 33")]
+    fn requantize_from_too_many_bits() {
+        requantize(0, 33, 16, None);
+    }
+
+    #[test]
+    #[should_panic(expected = "sample size must be from 1 to 32 bits, not 0")]
+    fn from_raw_zero_bits() {
+        f64::from_raw(0, 0);
+    }
+}
@@ -0,0 +1,915 @@
+//! Reading files without loading them whole into memory.
+
+use crate::chunks::inst::LoopRange;
+use crate::chunks::*;
+use crate::error::*;
+use crate::looping::LoopedFrames;
+use crate::samples::{
+    check_sample_size, convert_sample, requantize, sample_frame_capacity, Dither, Encoding, Sample,
+    SampleFormat,
+};
+use crate::types::*;
+use crate::validation::{ParseMode, Validator, Violation};
+use ::vadpcm::{Decoder, FrameFormat, SAMPLES_PER_FRAME};
+use std::convert::TryInto;
+use std::io::{Read, Seek, SeekFrom};
+use std::marker::PhantomData;
+
+/// Amount of sound data read at once when streaming samples.
+const BLOCK_SIZE: usize = 0x4000;
+
+/// Reads the ID and size of the chunk at the given position, if the ID is valid.
+fn read_chunk_header(reader: &mut (impl Read + Seek), position: u64) -> Result<Option<(ID, u32)>> {
+    let mut header = [0; 8];
+    reader.seek(SeekFrom::Start(position))?;
+    reader.read_exact(&mut header)?;
+    let id = match header[0..4].try_into() {
+        Ok(id) => id,
+        Err(_) => return Ok(None),
+    };
+    Ok(Some((
+        id,
+        u32::from_be_bytes(header[4..8].try_into().unwrap()),
+    )))
+}
+
+/// Whether a chunk header with a valid ID is found at the given position.
+fn is_chunk_header(reader: &mut (impl Read + Seek), position: u64, form_end: u64) -> Result<bool> {
+    if position + 8 > form_end {
+        return Ok(false);
+    }
+    Ok(read_chunk_header(reader, position)?.is_some())
+}
+
+/// The location of a chunk within the file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ChunkEntry {
+    pub id: ID,
+    /// Offset of the chunk data, past its header.
+    pub offset: u64,
+    pub size: u32,
+}
+
+/// A file read over `Read + Seek`. Only the chunk directory and the common chunk are read
+/// upfront; everything else is read on demand.
+#[derive(Debug)]
+pub struct AiffStream<R> {
+    reader: R,
+    form_type: FormType,
+    chunks: Vec<ChunkEntry>,
+    comm: comm::CommonChunk,
+    ssnd: ChunkEntry,
+    warnings: Vec<Violation>,
+}
+
+impl<R: Read + Seek> AiffStream<R> {
+    pub fn new(reader: R) -> Result<Self> {
+        Self::with_mode(reader, ParseMode::Normal)
+    }
+
+    /// Reads the chunk directory, checking the file against the spec according to the mode.
+    pub fn with_mode(mut reader: R, mode: ParseMode) -> Result<Self> {
+        let mut validator = Validator::new(mode);
+        let len = reader.seek(SeekFrom::End(0))?;
+        reader.seek(SeekFrom::Start(0))?;
+
+        if len < 12 {
+            return Err(AiffError::InvalidFormat {
+                offset: 0,
+                reason: "file is too short for a FORM header",
+            });
+        }
+        let mut header = [0; 12];
+        reader.read_exact(&mut header)?;
+        if &header[0..4] != b"FORM" {
+            return Err(AiffError::InvalidFormat {
+                offset: 0,
+                reason: "not a FORM chunk",
+            });
+        }
+        let mut form_end = 8 + u32::from_be_bytes(header[4..8].try_into().unwrap()) as u64;
+        if form_end != len {
+            let violation = Violation::FormSize {
+                declared: form_end,
+                actual: len,
+            };
+            if form_end > len || form_end < 12 {
+                validator.fail(
+                    violation,
+                    AiffError::InvalidFormat {
+                        offset: 4,
+                        reason: "FORM size doesn't match the file",
+                    },
+                )?;
+                form_end = len;
+            } else {
+                // data past the FORM chunk is ignored
+                validator.warn(violation);
+            }
+        }
+        let form_type = match &header[8..12] {
+            b"AIFF" => FormType::Aiff,
+            b"AIFC" => FormType::Aifc,
+            id => return Err(AiffError::InvalidFormType(id.try_into()?)),
+        };
+
+        let mut chunks = Vec::new();
+        let mut position = 12;
+        while position < form_end {
+            if position + 8 > form_end {
+                validator.fail(
+                    Violation::TrailingData {
+                        offset: position,
+                        size: form_end - position,
+                    },
+                    AiffError::InvalidFormat {
+                        offset: position,
+                        reason: "chunk header runs past the end of the FORM chunk",
+                    },
+                )?;
+                break;
+            }
+            let (id, size) =
+                read_chunk_header(&mut reader, position)?.ok_or(AiffError::InvalidFormat {
+                    offset: position,
+                    reason: "invalid chunk ID",
+                })?;
+            let offset = position + 8;
+            let mut size = size as u64;
+            if offset + size > form_end {
+                validator.fail(
+                    Violation::ChunkPastEnd {
+                        id,
+                        offset: position,
+                        excess: offset + size - form_end,
+                    },
+                    AiffError::InvalidFormat {
+                        offset: position,
+                        reason: "chunk runs past the end of the FORM chunk",
+                    },
+                )?;
+                size = form_end - offset;
+            }
+
+            chunks.push(ChunkEntry {
+                id,
+                offset,
+                size: size as u32,
+            });
+            position = offset + size + size % 2;
+
+            // some writers leave out the padding byte of odd-sized chunks
+            if size % 2 == 1 && validator.collects() {
+                let unpadded = position - 1;
+                let missing = unpadded == form_end
+                    || (unpadded + 8 <= form_end
+                        && !is_chunk_header(&mut reader, position, form_end)?
+                        && is_chunk_header(&mut reader, unpadded, form_end)?);
+                if missing {
+                    validator.warn(Violation::MissingPadding {
+                        id,
+                        offset: offset - 8,
+                    });
+                    position = unpadded;
+                }
+            }
+        }
+
+        // some writers add bytes to the end of the common chunk of AIFF files, which are ignored
+        if form_type == FormType::Aiff && validator.collects() {
+            let comm_entry = chunks.iter_mut().rfind(|entry| entry.id.data() == b"COMM");
+            if let Some(entry) = comm_entry.filter(|entry| entry.size > 18) {
+                validator.warn(Violation::CommonChunkSize {
+                    offset: entry.offset - 8,
+                    size: entry.size,
+                });
+                entry.size = 18;
+            }
+        }
+
+        // like when reading the whole file, the last of duplicated chunks is the one used
+        let find = |id: &[u8; 4]| chunks.iter().rfind(|entry| entry.id.data() == id).copied();
+        let comm_entry = find(b"COMM").ok_or(AiffError::MissingComm)?;
+        let ssnd = find(b"SSND").ok_or(AiffError::MissingSsnd)?;
+
+        let mut stream = Self {
+            reader,
+            form_type,
+            chunks,
+            // replaced right below
+            comm: comm::CommonChunk {
+                num_channels: 0,
+                num_sample_frames: 0,
+                sample_size: 0,
+                sample_rate: 0.0,
+                compression: None,
+            },
+            ssnd,
+            warnings: Vec::new(),
+        };
+        stream.comm =
+            stream.read_chunk(comm_entry, |data| comm::CommonChunk::read(data, form_type))?;
+
+        // reading the samples stops at the end of the sound data anyway. Sound data holding more
+        // sample frames than declared isn't a violation, as it can be padding, or the unused end
+        // of the last frame of compressed data
+        let capacity = stream
+            .read_sound_data_header()
+            .ok()
+            .and_then(|data_len| sample_frame_capacity(&stream.comm, data_len as u64));
+        if let Some(capacity) = capacity {
+            let declared = stream.comm.num_sample_frames;
+            if declared as u64 > capacity {
+                let capacity = capacity as u32;
+                validator.warn(Violation::SampleFrameCount {
+                    declared,
+                    actual: capacity,
+                });
+                if validator.collects() {
+                    stream.comm.num_sample_frames = capacity;
+                }
+            }
+        }
+
+        stream.warnings = validator.finish()?;
+        Ok(stream)
+    }
+
+    pub fn form_type(&self) -> FormType {
+        self.form_type
+    }
+
+    pub fn comm(&self) -> &comm::CommonChunk {
+        &self.comm
+    }
+
+    /// The violations of the spec that were repaired, outside of normal mode.
+    pub fn warnings(&self) -> &[Violation] {
+        &self.warnings
+    }
+
+    /// Every chunk in the file, in order.
+    pub fn chunks(&self) -> &[ChunkEntry] {
+        &self.chunks
+    }
+
+    pub fn into_inner(self) -> R {
+        self.reader
+    }
+
+    /// Reads the data of a chunk.
+    pub fn chunk_data(&mut self, entry: &ChunkEntry) -> Result<Vec<u8>> {
+        let mut data = vec![0; entry.size as usize];
+        self.reader.seek(SeekFrom::Start(entry.offset))?;
+        self.reader.read_exact(&mut data)?;
+        Ok(data)
+    }
+
+    /// Reads a chunk with the given function, locating its errors.
+    fn read_chunk<T>(
+        &mut self,
+        entry: ChunkEntry,
+        read: impl FnOnce(&[u8]) -> Result<T>,
+    ) -> Result<T> {
+        self.chunk_data(&entry)
+            .and_then(|data| read(&data))
+            .map_err(|err| err.in_chunk(entry.id, entry.offset))
+    }
+
+    fn entries(&self, id: &[u8; 4]) -> Vec<ChunkEntry> {
+        self.chunks
+            .iter()
+            .filter(|entry| entry.id.data() == id)
+            .copied()
+            .collect()
+    }
+
+    /// Reads the last chunk with the given ID, which is the one used when it's duplicated.
+    fn find_chunk<T>(
+        &mut self,
+        id: &[u8; 4],
+        read: impl FnOnce(&[u8]) -> Result<T>,
+    ) -> Result<Option<T>> {
+        match self.entries(id).last() {
+            Some(&entry) => self.read_chunk(entry, read).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// Reads every chunk with the given ID.
+    fn find_all_chunks<T>(
+        &mut self,
+        id: &[u8; 4],
+        read: impl Fn(&[u8]) -> Result<T>,
+    ) -> Result<Vec<T>> {
+        self.entries(id)
+            .into_iter()
+            .map(|entry| self.read_chunk(entry, &read))
+            .collect()
+    }
+
+    /// Reads the last VADPCM application-specific chunk with the given name, passing its data
+    /// without its header.
+    fn find_vadpcm_chunk<T>(
+        &mut self,
+        name: &str,
+        read: impl Fn(&[u8]) -> Result<T>,
+    ) -> Result<Option<T>> {
+        for entry in self.entries(b"APPL").into_iter().rev() {
+            let chunk = self.read_chunk(entry, |data| {
+                let (signature, appl_name, appl_data) = vadpcm::read_appl_header(data)?;
+                if signature.data() == vadpcm::SIGNATURE && appl_name == name {
+                    read(appl_data).map(Some)
+                } else {
+                    Ok(None)
+                }
+            })?;
+            if chunk.is_some() {
+                return Ok(chunk);
+            }
+        }
+        Ok(None)
+    }
+
+    pub fn fver(&mut self) -> Result<Option<fver::FormatVersionChunk>> {
+        if self.form_type != FormType::Aifc {
+            return Ok(None);
+        }
+        self.find_chunk(b"FVER", fver::FormatVersionChunk::read)
+    }
+
+    pub fn mark(&mut self) -> Result<Option<mark::MarkerChunk>> {
+        self.find_chunk(b"MARK", mark::MarkerChunk::read)
+    }
+
+    pub fn inst(&mut self) -> Result<Option<inst::InstrumentChunk>> {
+        self.find_chunk(b"INST", inst::InstrumentChunk::read)
+    }
+
+    pub fn vadpcm_codes(&mut self) -> Result<Option<vadpcm::VadpcmCodesChunk>> {
+        self.find_vadpcm_chunk(
+            vadpcm::VadpcmCodesChunk::NAME,
+            vadpcm::VadpcmCodesChunk::read,
+        )
+    }
+
+    pub fn vadpcm_loops(&mut self) -> Result<Option<vadpcm::VadpcmLoopsChunk>> {
+        self.find_vadpcm_chunk(
+            vadpcm::VadpcmLoopsChunk::NAME,
+            vadpcm::VadpcmLoopsChunk::read,
+        )
+    }
+
+    pub fn midi(&mut self) -> Result<Vec<midi::MidiDataChunk>> {
+        self.find_all_chunks(b"MIDI", midi::MidiDataChunk::read)
+    }
+
+    pub fn aesd(&mut self) -> Result<Option<aesd::AudioRecordingChunk>> {
+        self.find_chunk(b"AESD", aesd::AudioRecordingChunk::read)
+    }
+
+    /// Application-specific chunks other than the VADPCM ones.
+    pub fn appl(&mut self) -> Result<Vec<appl::ApplicationSpecificChunk>> {
+        let chunks = self.find_all_chunks(b"APPL", |data| {
+            let (signature, name, _) = vadpcm::read_appl_header(data)?;
+            let is_vadpcm = signature.data() == vadpcm::SIGNATURE
+                && (name == vadpcm::VadpcmCodesChunk::NAME
+                    || name == vadpcm::VadpcmLoopsChunk::NAME);
+            if is_vadpcm {
+                Ok(None)
+            } else {
+                appl::ApplicationSpecificChunk::read(data).map(Some)
+            }
+        })?;
+        Ok(chunks.into_iter().flatten().collect())
+    }
+
+    pub fn comt(&mut self) -> Result<Option<comt::CommentsChunk>> {
+        self.find_chunk(b"COMT", comt::CommentsChunk::read)
+    }
+
+    pub fn name(&mut self) -> Result<Option<text::TextChunk>> {
+        self.find_chunk(b"NAME", text::TextChunk::read)
+    }
+
+    pub fn author(&mut self) -> Result<Option<text::TextChunk>> {
+        self.find_chunk(b"AUTH", text::TextChunk::read)
+    }
+
+    pub fn copyright(&mut self) -> Result<Option<text::TextChunk>> {
+        self.find_chunk(b"(c) ", text::TextChunk::read)
+    }
+
+    pub fn annotations(&mut self) -> Result<Vec<text::TextChunk>> {
+        self.find_all_chunks(b"ANNO", text::TextChunk::read)
+    }
+
+    /// Seeks to the sound data, returning its size.
+    fn read_sound_data_header(&mut self) -> Result<usize> {
+        let mut header = [0; ssnd::SoundDataChunk::HEADER_SIZE];
+        if (self.ssnd.size as usize) < header.len() {
+            return Err(AiffError::UnexpectedEnd);
+        }
+        self.reader.seek(SeekFrom::Start(self.ssnd.offset))?;
+        self.reader.read_exact(&mut header)?;
+        let (offset, _) = ssnd::SoundDataChunk::read_header(&header)?;
+        let data_len = (self.ssnd.size as usize - header.len())
+            .checked_sub(offset as usize)
+            .ok_or(AiffError::UnexpectedEnd)?;
+        self.reader.seek(SeekFrom::Current(offset as i64))?;
+        Ok(data_len)
+    }
+
+    /// Streams the samples from the sound data chunk, a block at a time, decoding them if
+    /// they're compressed with VADPCM. Loops are not followed.
+    pub fn samples(&mut self) -> Result<StreamSamples<'_, R>> {
+        let codes = self.vadpcm_codes()?;
+        let encoding = Encoding::new(&self.comm, codes.as_ref())?;
+        let sample_size = encoding.sample_size(&self.comm);
+        let remaining_samples = encoding.num_samples(&self.comm);
+        let decoder = match encoding {
+            Encoding::Format(format) => StreamDecoder::Format(format),
+            Encoding::Vadpcm(book) => StreamDecoder::Vadpcm {
+                decoder: Decoder::new(book, FrameFormat::Bits4),
+                frame_index: 0,
+            },
+        };
+
+        let ssnd = self.ssnd;
+        let data_len = self
+            .read_sound_data_header()
+            .map_err(|err| err.in_chunk(ssnd.id, ssnd.offset))?;
+
+        Ok(StreamSamples {
+            reader: &mut self.reader,
+            decoder,
+            remaining_bytes: data_len,
+            remaining_samples,
+            num_channels: self.comm.num_channels,
+            sample_size,
+            dither: None,
+            block: Vec::new(),
+            position: 0,
+        })
+    }
+}
+
+#[derive(Debug)]
+enum StreamDecoder {
+    Format(SampleFormat),
+    Vadpcm {
+        decoder: Decoder,
+        frame_index: usize,
+    },
+}
+
+/// An iterator over samples read from a stream. Reading errors end the iteration after being
+/// returned.
+#[derive(Debug)]
+pub struct StreamSamples<'s, R> {
+    reader: &'s mut R,
+    decoder: StreamDecoder,
+    remaining_bytes: usize,
+    /// Samples left across all channels.
+    remaining_samples: u64,
+    num_channels: u16,
+    sample_size: u16,
+    dither: Option<Dither>,
+    /// The samples decoded out of the last block read.
+    block: Vec<i32>,
+    position: usize,
+}
+
+impl<'s, R: Read> StreamSamples<'s, R> {
+    /// Groups the samples into frames holding one sample per channel.
+    pub fn frames<S: Sample>(self) -> StreamFrames<'s, R, S> {
+        StreamFrames {
+            samples: self,
+            _sample: PhantomData,
+        }
+    }
+
+    /// Dithers the samples whenever their bit depth gets reduced.
+    pub fn with_dither(mut self, dither: Dither) -> Self {
+        self.dither = Some(dither);
+        self
+    }
+
+    /// Converts the samples, without grouping them into frames.
+    pub fn convert<S: Sample>(mut self) -> impl Iterator<Item = Result<S>> + 's
+    where
+        R: 's,
+    {
+        let sample_size = self.sample_size;
+        let mut dither = self.dither.take();
+        self.map(move |value| Ok(convert_sample(value?, sample_size, dither.as_mut())))
+    }
+
+    /// Requantizes the samples to `sample_size` bits. Panics if it isn't from 1 to 32.
+    pub fn requantize(mut self, sample_size: u16) -> impl Iterator<Item = Result<i32>> + 's
+    where
+        R: 's,
+    {
+        check_sample_size(sample_size);
+        let from_bits = self.sample_size;
+        let mut dither = self.dither.take();
+        self.map(move |value| Ok(requantize(value?, from_bits, sample_size, dither.as_mut())))
+    }
+
+    /// Reads and decodes the next block of sound data.
+    fn read_block(&mut self) -> Result<()> {
+        let unit = match &self.decoder {
+            StreamDecoder::Format(format) => format.unit_size(self.sample_size, self.num_channels),
+            StreamDecoder::Vadpcm { .. } => FrameFormat::Bits4.frame_size(),
+        };
+        // only read whole samples or frames
+        let len = BLOCK_SIZE.min(self.remaining_bytes) / unit * unit;
+
+        let mut data = vec![0; len];
+        self.reader.read_exact(&mut data)?;
+        self.remaining_bytes -= len;
+
+        self.block.clear();
+        self.position = 0;
+        match &mut self.decoder {
+            StreamDecoder::Format(format) => {
+                for unit in data.chunks_exact(unit) {
+                    format.decode_unit(unit, self.sample_size, self.num_channels, &mut self.block);
+                }
+            }
+            StreamDecoder::Vadpcm {
+                decoder,
+                frame_index,
+            } => {
+                self.block.reserve(len / unit * SAMPLES_PER_FRAME);
+                for frame in data.chunks_exact(unit) {
+                    let samples = decoder.decode_frame(frame, *frame_index)?;
+                    self.block.extend(samples.iter().map(|&s| s as i32));
+                    *frame_index += 1;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<'s, R: Read> Iterator for StreamSamples<'s, R> {
+    type Item = Result<i32>;
+
+    fn next(&mut self) -> Option<Result<i32>> {
+        if self.remaining_samples == 0 {
+            return None;
+        }
+
+        if self.position == self.block.len() {
+            if let Err(err) = self.read_block() {
+                self.remaining_samples = 0;
+                return Some(Err(err));
+            }
+            if self.block.is_empty() {
+                return None;
+            }
+        }
+
+        let value = self.block[self.position];
+        self.position += 1;
+        self.remaining_samples -= 1;
+        Some(Ok(value))
+    }
+}
+
+/// An iterator over sample frames read from a stream, each holding one sample per channel.
+#[derive(Debug)]
+pub struct StreamFrames<'s, R, S> {
+    samples: StreamSamples<'s, R>,
+    _sample: PhantomData<S>,
+}
+
+impl<'s, R: Read, S: Sample> StreamFrames<'s, R, S> {
+    /// Splits the samples by channel.
+    pub fn deinterleave(self) -> Result<Vec<Vec<S>>> {
+        let mut channels = vec![Vec::new(); self.samples.num_channels as usize];
+        for frame in self {
+            for (channel, sample) in channels.iter_mut().zip(frame?) {
+                channel.push(sample);
+            }
+        }
+        Ok(channels)
+    }
+
+    /// Mixes every channel down to one.
+    pub fn downmix(self) -> impl Iterator<Item = Result<S>> + 's
+    where
+        R: 's,
+        S: 's,
+    {
+        self.map(|frame| frame.map(|frame| S::average(&frame)))
+    }
+
+    /// Reads every frame, then plays them following the given loops.
+    pub fn looped(
+        self,
+        sustain_loop: Option<LoopRange>,
+        release_loop: Option<LoopRange>,
+    ) -> Result<LoopedFrames<S>> {
+        Ok(LoopedFrames::new(
+            self.collect::<Result<_>>()?,
+            sustain_loop,
+            release_loop,
+        ))
+    }
+}
+
+impl<'s, R: Read, S: Sample> Iterator for StreamFrames<'s, R, S> {
+    type Item = Result<Vec<S>>;
+
+    fn next(&mut self) -> Option<Result<Vec<S>>> {
+        let num_channels = self.samples.num_channels as usize;
+        let sample_size = self.samples.sample_size;
+
+        let mut frame = Vec::with_capacity(num_channels);
+        for _ in 0..num_channels {
+            match self.samples.next()? {
+                Ok(value) => frame.push(convert_sample(
+                    value,
+                    sample_size,
+                    self.samples.dither.as_mut(),
+                )),
+                Err(err) => return Some(Err(err)),
+            }
+        }
+
+        if frame.is_empty() {
+            None
+        } else {
+            Some(Ok(frame))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::validation::Violations;
+    use std::io::Cursor;
+
+    /// Offsets of the chunks written by `file`.
+    const COMM_OFFSET: u64 = 12;
+    const SSND_OFFSET: u64 = COMM_OFFSET + 8 + 18;
+    const END_OFFSET: u64 = SSND_OFFSET + 8 + 8 + 8;
+
+    fn chunk(id: &[u8; 4], data: &[u8]) -> Vec<u8> {
+        let mut chunk = Vec::new();
+        write_chunk(&mut chunk, ID::new(*id), data).unwrap();
+        chunk
+    }
+
+    /// A mono 16-bit AIFF file declaring `num_sample_frames` but holding 4, followed by the
+    /// extra chunk data, with a FORM size matching the whole.
+    fn file(num_sample_frames: u32, extra: &[u8]) -> Vec<u8> {
+        let mut comm = vec![0, 1];
+        comm.extend_from_slice(&num_sample_frames.to_be_bytes());
+        comm.extend_from_slice(&[0, 16]);
+        comm.extend_from_slice(&write_f80(8000.0));
+
+        let mut chunks = chunk(b"COMM", &comm);
+        chunks.extend(chunk(b"SSND", &[0; 16]));
+        chunks.extend_from_slice(extra);
+
+        let mut data = b"FORM".to_vec();
+        data.extend_from_slice(&(4 + chunks.len() as u32).to_be_bytes());
+        data.extend_from_slice(b"AIFF");
+        data.extend(chunks);
+        data
+    }
+
+    fn open(data: &[u8], mode: ParseMode) -> Result<AiffStream<Cursor<&[u8]>>> {
+        AiffStream::with_mode(Cursor::new(data), mode)
+    }
+
+    /// The offset of the format error in normal mode.
+    fn format_error(data: &[u8]) -> u64 {
+        match open(data, ParseMode::Normal) {
+            Err(AiffError::InvalidFormat { offset, .. }) => offset,
+            result => panic!("expected a format error, got {:?}", result),
+        }
+    }
+
+    /// The violations reported in strict mode.
+    fn violations(data: &[u8]) -> Vec<Violation> {
+        match open(data, ParseMode::Strict) {
+            Err(AiffError::Violations(Violations(violations))) => violations,
+            result => panic!("expected violations, got {:?}", result),
+        }
+    }
+
+    #[test]
+    fn valid() {
+        let data = file(4, &[]);
+        for &mode in &[ParseMode::Strict, ParseMode::Normal, ParseMode::Lenient] {
+            let stream = open(&data, mode).unwrap();
+            assert!(stream.warnings().is_empty());
+            assert_eq!(stream.comm().num_sample_frames, 4);
+            let ids: Vec<_> = stream.chunks().iter().map(|entry| entry.id).collect();
+            assert_eq!(ids, [ID::new(*b"COMM"), ID::new(*b"SSND")]);
+        }
+    }
+
+    #[test]
+    fn truncated_form_header() {
+        let data = &file(4, &[])[..10];
+        for &mode in &[ParseMode::Strict, ParseMode::Normal, ParseMode::Lenient] {
+            assert!(matches!(
+                open(data, mode),
+                Err(AiffError::InvalidFormat { offset: 0, .. })
+            ));
+        }
+    }
+
+    #[test]
+    fn chunk_past_end() {
+        // declares 10 bytes, holds 4
+        let mut anno = chunk(b"ANNO", &[0; 10]);
+        anno.truncate(8 + 4);
+        let data = file(4, &anno);
+
+        assert_eq!(format_error(&data), END_OFFSET);
+        let violation = Violation::ChunkPastEnd {
+            id: ID::new(*b"ANNO"),
+            offset: END_OFFSET,
+            excess: 6,
+        };
+        assert_eq!(violations(&data), vec![violation.clone()]);
+
+        let stream = open(&data, ParseMode::Lenient).unwrap();
+        assert_eq!(stream.warnings(), [violation]);
+        assert_eq!(
+            stream.chunks().last(),
+            Some(&ChunkEntry {
+                id: ID::new(*b"ANNO"),
+                offset: END_OFFSET + 8,
+                size: 4,
+            })
+        );
+    }
+
+    #[test]
+    fn missing_padding() {
+        let mut extra = chunk(b"NAME", b"abc");
+        extra.pop();
+        extra.extend(chunk(b"AUTH", b"ab"));
+        let data = file(4, &extra);
+
+        // the next header is read a byte late, starting with the size of the NAME chunk
+        assert_eq!(format_error(&data), END_OFFSET + 8 + 4);
+        let violation = Violation::MissingPadding {
+            id: ID::new(*b"NAME"),
+            offset: END_OFFSET,
+        };
+        assert_eq!(violations(&data), vec![violation.clone()]);
+
+        let stream = open(&data, ParseMode::Lenient).unwrap();
+        assert_eq!(stream.warnings(), [violation]);
+        assert_eq!(
+            stream.chunks()[2..],
+            [
+                ChunkEntry {
+                    id: ID::new(*b"NAME"),
+                    offset: END_OFFSET + 8,
+                    size: 3,
+                },
+                ChunkEntry {
+                    id: ID::new(*b"AUTH"),
+                    offset: END_OFFSET + 8 + 3 + 8,
+                    size: 2,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn inflated_sample_frame_count() {
+        let data = file(10, &[]);
+
+        // not in the way of reading the samples
+        let stream = open(&data, ParseMode::Normal).unwrap();
+        assert!(stream.warnings().is_empty());
+        assert_eq!(stream.comm().num_sample_frames, 10);
+
+        let violation = Violation::SampleFrameCount {
+            declared: 10,
+            actual: 4,
+        };
+        assert_eq!(violations(&data), vec![violation.clone()]);
+
+        let stream = open(&data, ParseMode::Lenient).unwrap();
+        assert_eq!(stream.warnings(), [violation]);
+        assert_eq!(stream.comm().num_sample_frames, 4);
+    }
+
+    #[test]
+    fn oversized_comm() {
+        let mut data = file(4, &[]);
+        data[16..20].copy_from_slice(&20u32.to_be_bytes());
+        data.splice(SSND_OFFSET as usize..SSND_OFFSET as usize, vec![0; 2]);
+        data[4..8].copy_from_slice(&(END_OFFSET as u32 - 8 + 2).to_be_bytes());
+
+        assert!(open(&data, ParseMode::Normal).is_err());
+        let violation = Violation::CommonChunkSize {
+            offset: COMM_OFFSET,
+            size: 20,
+        };
+        assert_eq!(violations(&data), vec![violation.clone()]);
+
+        let stream = open(&data, ParseMode::Lenient).unwrap();
+        assert_eq!(stream.warnings(), [violation]);
+        assert_eq!(stream.chunks()[0].size, 18);
+        assert_eq!(stream.comm().num_sample_frames, 4);
+
+        let reader = crate::AiffReader {
+            mode: ParseMode::Lenient,
+            ..crate::AiffReader::all()
+        };
+        assert_eq!(reader.read(&data).unwrap().comm, *stream.comm());
+    }
+
+    #[test]
+    fn form_size_too_large() {
+        let mut data = file(4, &[]);
+        data[4..8].copy_from_slice(&(END_OFFSET as u32 - 8 + 6).to_be_bytes());
+
+        assert_eq!(format_error(&data), 4);
+        let violation = Violation::FormSize {
+            declared: END_OFFSET + 6,
+            actual: END_OFFSET,
+        };
+        assert_eq!(violations(&data), vec![violation.clone()]);
+
+        let stream = open(&data, ParseMode::Lenient).unwrap();
+        assert_eq!(stream.warnings(), [violation]);
+        assert_eq!(stream.chunks().len(), 2);
+    }
+
+    #[test]
+    fn form_size_too_small() {
+        // a chunk left past the end of the FORM chunk is ignored
+        let mut data = file(4, &chunk(b"NAME", b"ab"));
+        data[4..8].copy_from_slice(&(END_OFFSET as u32 - 8).to_be_bytes());
+
+        let stream = open(&data, ParseMode::Normal).unwrap();
+        assert!(stream.warnings().is_empty());
+        assert_eq!(stream.chunks().len(), 2);
+
+        let violation = Violation::FormSize {
+            declared: END_OFFSET,
+            actual: END_OFFSET + 10,
+        };
+        assert_eq!(violations(&data), vec![violation.clone()]);
+
+        let stream = open(&data, ParseMode::Lenient).unwrap();
+        assert_eq!(stream.warnings(), [violation]);
+        assert_eq!(stream.chunks().len(), 2);
+    }
+
+    #[test]
+    fn duplicate_chunks() {
+        let marks = |position| mark::MarkerChunk {
+            markers: vec![mark::Marker {
+                id: 1,
+                position,
+                name: "start".to_string(),
+            }],
+        };
+        let name = |text: &str| text::TextChunk {
+            text: text.to_string(),
+        };
+        let codes = |coef| vadpcm::VadpcmCodesChunk {
+            version: 1,
+            book: ::vadpcm::Codebook::new(2, 1, vec![coef; 16]).unwrap(),
+        };
+
+        let mut extra = Vec::new();
+        for (i, text) in ["first", "second"].iter().enumerate() {
+            let mut data = Vec::new();
+            marks(i as u32).write(&mut data).unwrap();
+            extra.extend(chunk(b"MARK", &data));
+            data.clear();
+            name(text).write(&mut data).unwrap();
+            extra.extend(chunk(b"NAME", &data));
+            data.clear();
+            codes(i as i16).write(&mut data).unwrap();
+            extra.extend(chunk(b"APPL", &data));
+        }
+        let data = file(4, &extra);
+
+        // the stream agrees with reading the whole file on the last one being used
+        let aiff = crate::AiffReader::all().read(&data).unwrap();
+        let mut stream = open(&data, ParseMode::Normal).unwrap();
+        assert_eq!(aiff.mark, Some(marks(1)));
+        assert_eq!(stream.mark().unwrap(), aiff.mark);
+        assert_eq!(aiff.name, Some(name("second")));
+        assert_eq!(stream.name().unwrap(), aiff.name);
+        assert_eq!(aiff.vadpcm_codes, Some(codes(1)));
+        assert_eq!(stream.vadpcm_codes().unwrap(), aiff.vadpcm_codes);
+    }
+}
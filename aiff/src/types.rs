@@ -1,12 +1,18 @@
 use crate::error::*;
-use byteorder::{ByteOrder, BE};
+use byteorder::{ByteOrder, WriteBytesExt, BE};
+use std::borrow::Cow;
 use std::convert::TryInto;
 use std::fmt;
+use std::io::Write;
 
-#[derive(Hash, PartialEq, Eq)]
+#[derive(Clone, Copy, Hash, PartialEq, Eq)]
 pub struct ID([u8; 4]);
 
 impl ID {
+    pub const fn new(data: [u8; 4]) -> Self {
+        Self(data)
+    }
+
     pub fn data(&self) -> &[u8] {
         &self.0
     }
@@ -16,7 +22,7 @@ impl<'a> std::convert::TryFrom<&'a [u8]> for ID {
     type Error = AiffError;
 
     fn try_from(value: &'a [u8]) -> Result<Self> {
-        assert_eq!(value.len(), 4);
+        let value: [u8; 4] = value.try_into().map_err(|_| AiffError::UnexpectedEnd)?;
 
         let mut has_spaces = false;
         for b in value.iter() {
@@ -26,15 +32,15 @@ impl<'a> std::convert::TryFrom<&'a [u8]> for ID {
                 }
                 0x21..=0x7e => {
                     if has_spaces {
-                        return Err(AiffError::InvalidFormat);
+                        return Err(AiffError::InvalidId(value));
                     }
                 }
 
-                _ => return Err(AiffError::InvalidFormat),
+                _ => return Err(AiffError::InvalidId(value)),
             }
         }
 
-        Ok(Self(value.try_into().unwrap()))
+        Ok(Self(value))
     }
 }
 
@@ -56,80 +62,344 @@ impl fmt::Display for ID {
     }
 }
 
-pub fn read_chunk<'a>(data: &mut &'a [u8]) -> Result<(ID, &'a [u8])> {
-    if data.len() < 8 {
-        return Err(AiffError::InvalidFormat);
-    }
-    let id = &data[0..4];
-    let size = BE::read_u32(&data[4..8]);
+/// Whether a file is a plain AIFF one or an AIFF-C one, which may hold compressed data.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FormType {
+    Aiff,
+    Aifc,
+}
 
-    if data.len() < 8 + size as usize {
-        return Err(AiffError::InvalidFormat);
+impl FormType {
+    pub fn id(self) -> ID {
+        match self {
+            FormType::Aiff => ID(*b"AIFF"),
+            FormType::Aifc => ID(*b"AIFC"),
+        }
     }
-    let chunk_data = &data[8..][..size as usize];
+}
+
+/// What a chunk was parsed into. Indices point into the vectors of chunks that may appear
+/// several times.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ChunkKind {
+    Fver,
+    Comm,
+    Ssnd,
+    Mark,
+    Inst,
+    VadpcmCodes,
+    VadpcmLoops,
+    Midi(usize),
+    Aesd,
+    Appl(usize),
+    Comt,
+    Name,
+    Author,
+    Copyright,
+    Annotation(usize),
+    /// Not parsed, either unknown, disabled or overridden by a later chunk of the same type.
+    Other,
+}
 
-    *data = &data[8..][size as usize..];
-    if size % 2 == 1 {
-        *data = &data[1..];
+/// A chunk as found in a file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RawChunk<'a> {
+    pub id: ID,
+    /// Offset of the chunk's data in the file.
+    pub offset: u64,
+    pub data: Cow<'a, [u8]>,
+    pub kind: ChunkKind,
+}
+
+impl RawChunk<'_> {
+    /// Copies the chunk's data if it's borrowed.
+    pub fn into_owned(self) -> RawChunk<'static> {
+        RawChunk {
+            id: self.id,
+            offset: self.offset,
+            data: Cow::Owned(self.data.into_owned()),
+            kind: self.kind,
+        }
     }
+}
 
-    Ok((id.try_into().unwrap(), chunk_data))
+/// Writes a chunk, padding it to an even size.
+pub fn write_chunk(w: &mut impl Write, id: ID, data: &[u8]) -> Result<()> {
+    w.write_all(id.data())?;
+    w.write_u32::<BE>(data.len() as u32)?;
+    w.write_all(data)?;
+    if data.len() % 2 == 1 {
+        w.write_u8(0)?;
+    }
+    Ok(())
 }
 
-pub fn read_f80(data: &[u8]) -> f64 {
-    assert!(data.len() >= 10);
+/// Decodes an 80-bit extended float, rounding it to the nearest f64. Denormals and unnormals
+/// are accepted, but values too large for a f64 and invalid encodings are errors.
+pub fn read_f80(data: &[u8]) -> Result<f64> {
+    if data.len() < 10 {
+        return Err(AiffError::UnexpectedEnd);
+    }
     let exponent = BE::read_u16(&data[0..2]);
     let mantissa = BE::read_u64(&data[2..10]);
 
-    let sign = (exponent >> 15) != 0;
+    let sign = if (exponent >> 15) != 0 { -1.0 } else { 1.0 };
     let exponent = exponent & 0b0111_1111_1111_1111;
 
-    if exponent == 0 {
-        if (mantissa >> 63) == 0 {
-            if mantissa == 0 {
-                if !sign {
-                    0.0
-                } else {
-                    -0.0
-                }
-            } else {
-                unimplemented!("denormal f80")
-            }
-        } else {
-            unimplemented!("pseudo-denormal f80")
-        }
-    } else if exponent == (1 << 15) - 1 {
-        unimplemented!("infinity/nan f80")
-    } else if (mantissa >> 63) == 0 {
-        unimplemented!("unnormal f80")
-    } else {
-        let exponent = exponent as i32 - 16383;
-        if exponent < -1022 || exponent > 1023 {
-            unimplemented!("f80 exponent is too large");
-        }
+    if exponent == (1 << 15) - 1 {
+        // pseudo-infinities and pseudo-NaNs, without the integer bit, aren't valid
+        return match (mantissa >> 63, mantissa << 1) {
+            (0, _) => Err(AiffError::InvalidFloat),
+            (_, 0) => Ok(sign * f64::INFINITY),
+            _ => Ok(f64::NAN),
+        };
+    }
+    if mantissa == 0 {
+        return Ok(sign * 0.0);
+    }
+
+    // the value is `mantissa * 2^(exponent - 16383 - 63)`, denormals having the exponent 1;
+    // normalize it so that the integer bit is set
+    let shift = mantissa.leading_zeros();
+    let mantissa = mantissa << shift;
+    let exponent = exponent.max(1) as i32 - 16383 - shift as i32;
+    if exponent > 1023 {
+        return Err(AiffError::InvalidFloat);
+    }
+
+    // bits dropped to fit in the f64's mantissa, along with the integer bit when it's normal
+    let dropped = 11 + (-1022 - exponent).max(0) as u32;
+    if dropped > 64 {
+        // less than half the smallest denormal
+        return Ok(sign * 0.0);
+    }
+    let mantissa = mantissa as u128;
+    let mut bits = (mantissa >> dropped) as u64;
+    let remainder = mantissa & ((1 << dropped) - 1);
+    let half = 1 << (dropped - 1);
+    if remainder > half || (remainder == half && bits & 1 == 1) {
+        // this may carry into the exponent, even to infinity
+        bits += 1;
+    }
 
-        // construct the f64
-        let exponent = exponent + 1023;
-        let mantissa = mantissa >> (64 - 52);
-        f64::from_bits(mantissa as u64 | ((exponent as u64) << 52) | if sign { 1 << 63 } else { 0 })
+    if exponent >= -1022 {
+        // the integer bit adds one to the biased exponent
+        bits += ((exponent + 1022) as u64) << 52;
     }
+    let value = f64::from_bits(bits);
+    if value.is_infinite() {
+        return Err(AiffError::InvalidFloat);
+    }
+    Ok(sign * value)
 }
 
 pub fn read_pstring(data: &mut &[u8]) -> Result<String> {
     if data.is_empty() {
-        return Err(AiffError::InvalidFormat);
+        return Err(AiffError::UnexpectedEnd);
     }
     let len = data[0] as usize;
     *data = &data[1..];
 
     if data.len() < len {
-        return Err(AiffError::InvalidFormat);
+        return Err(AiffError::UnexpectedEnd);
     }
     let string_data = &data[..len];
     *data = &data[len..];
 
-    if len % 2 == 0 {
-        *data = &data[1..];
+    // pad to an even total length, the pad byte may be missing at the end of the chunk
+    if len.is_multiple_of(2) {
+        *data = data.get(1..).unwrap_or_default();
     }
     Ok(String::from_utf8_lossy(string_data).into_owned())
 }
+
+/// Encodes a f64 as an 80-bit extended float, which holds any of them exactly.
+pub fn write_f80(value: f64) -> [u8; 10] {
+    let bits = value.to_bits();
+    let sign = ((bits >> 63) as u16) << 15;
+    let exponent = ((bits >> 52) & 0x7ff) as i32;
+    let fraction = bits & ((1 << 52) - 1);
+
+    let (exponent, mantissa) = if exponent == 0x7ff {
+        // infinities and NaNs, keeping the payload
+        (0x7fff, (1 << 63) | (fraction << 11))
+    } else if exponent != 0 {
+        // make the integer bit explicit
+        (exponent - 1023 + 16383, (1 << 63) | (fraction << 11))
+    } else if fraction == 0 {
+        (0, 0)
+    } else {
+        // denormals are normal ones here
+        let shift = fraction.leading_zeros();
+        (-1022 - (shift as i32 - 11) + 16383, fraction << shift)
+    };
+
+    let mut data = [0; 10];
+    data[0..2].copy_from_slice(&(sign | exponent as u16).to_be_bytes());
+    data[2..10].copy_from_slice(&mantissa.to_be_bytes());
+    data
+}
+
+pub fn write_pstring(w: &mut impl Write, s: &str) -> Result<()> {
+    if s.len() > 255 {
+        return Err(AiffError::StringTooLong);
+    }
+
+    w.write_u8(s.len() as u8)?;
+    w.write_all(s.as_bytes())?;
+    // pad to an even total length
+    if (1 + s.len()) % 2 == 1 {
+        w.write_u8(0)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn f80(exponent: u16, mantissa: u64) -> [u8; 10] {
+        let mut data = [0; 10];
+        data[0..2].copy_from_slice(&exponent.to_be_bytes());
+        data[2..10].copy_from_slice(&mantissa.to_be_bytes());
+        data
+    }
+
+    fn read(exponent: u16, mantissa: u64) -> f64 {
+        read_f80(&f80(exponent, mantissa)).unwrap()
+    }
+
+    /// Exponent of the smallest f64 denormal, with the integer bit set.
+    const MIN_DENORMAL: u16 = 16383 - 1074;
+
+    #[test]
+    fn read_zero() {
+        assert_eq!(read(0, 0).to_bits(), 0.0f64.to_bits());
+        assert_eq!(read(0x8000, 0).to_bits(), (-0.0f64).to_bits());
+        // the exponent doesn't matter without any mantissa bit
+        assert_eq!(read(16383, 0).to_bits(), 0.0f64.to_bits());
+    }
+
+    #[test]
+    fn read_normal() {
+        assert_eq!(read(16383, 1 << 63), 1.0);
+        assert_eq!(read(0x8000 | 16384, 0xc000_0000_0000_0000), -3.0);
+        assert_eq!(read(16398, 0xac44_0000_0000_0000), 44100.0);
+    }
+
+    #[test]
+    fn read_denormal() {
+        let smallest = f64::from_bits(1);
+        assert_eq!(read(MIN_DENORMAL, 1 << 63), smallest);
+        assert_eq!(read(0x8000 | MIN_DENORMAL, 1 << 63), -smallest);
+        // halfway cases round to even
+        assert_eq!(read(MIN_DENORMAL - 1, 1 << 63).to_bits(), 0);
+        assert_eq!(read(MIN_DENORMAL, 0xc000_0000_0000_0000), 2.0 * smallest);
+        assert_eq!(
+            read(MIN_DENORMAL + 1, 0xa000_0000_0000_0000),
+            2.0 * smallest
+        );
+        // anything past half rounds up
+        assert_eq!(read(MIN_DENORMAL - 1, (1 << 63) | 1), smallest);
+        assert_eq!(read(MIN_DENORMAL - 1, 0xc000_0000_0000_0000), smallest);
+        // far below it
+        assert_eq!(read(1, 1 << 63).to_bits(), 0);
+        assert_eq!(read(0x8001, 1 << 63).to_bits(), (-0.0f64).to_bits());
+        // the largest denormal, and halfway from it to the smallest normal
+        assert_eq!(
+            read(16383 - 1023, 0xffff_ffff_ffff_f000),
+            f64::MIN_POSITIVE - smallest
+        );
+        assert_eq!(read(16383 - 1023, 0xffff_ffff_ffff_f800), f64::MIN_POSITIVE);
+    }
+
+    #[test]
+    fn read_unnormal() {
+        // without the integer bit
+        assert_eq!(read(16383, 1 << 62), 0.5);
+        assert_eq!(read(16383, 1), 2.0f64.powi(-63));
+        // denormal f80s, and pseudo-denormals with the integer bit, are both far too small
+        assert_eq!(read(0, 1).to_bits(), 0);
+        assert_eq!(read(0, 1 << 63).to_bits(), 0);
+        assert_eq!(read(0x8000, 1 << 63).to_bits(), (-0.0f64).to_bits());
+    }
+
+    #[test]
+    fn read_special() {
+        assert_eq!(read(0x7fff, 1 << 63), f64::INFINITY);
+        assert_eq!(read(0xffff, 1 << 63), f64::NEG_INFINITY);
+        assert!(read(0x7fff, 0xc000_0000_0000_0000).is_nan());
+        assert!(read(0xffff, (1 << 63) | 1).is_nan());
+        // pseudo-infinity and pseudo-NaN
+        assert!(matches!(
+            read_f80(&f80(0x7fff, 0)),
+            Err(AiffError::InvalidFloat)
+        ));
+        assert!(matches!(
+            read_f80(&f80(0x7fff, 1 << 62)),
+            Err(AiffError::InvalidFloat)
+        ));
+    }
+
+    #[test]
+    fn read_overflow() {
+        assert_eq!(read(16383 + 1023, u64::MAX << 11), f64::MAX);
+        // too large, either directly or once rounded
+        assert!(matches!(
+            read_f80(&f80(16383 + 1024, 1 << 63)),
+            Err(AiffError::InvalidFloat)
+        ));
+        assert!(matches!(
+            read_f80(&f80(0x7ffe, 1 << 63)),
+            Err(AiffError::InvalidFloat)
+        ));
+        assert!(matches!(
+            read_f80(&f80(16383 + 1023, u64::MAX)),
+            Err(AiffError::InvalidFloat)
+        ));
+        // an unnormal may still fit
+        assert_eq!(read(16383 + 1024, 1 << 62), 2.0f64.powi(1023));
+    }
+
+    #[test]
+    fn read_short() {
+        assert!(matches!(read_f80(&[0; 9]), Err(AiffError::UnexpectedEnd)));
+    }
+
+    #[test]
+    fn write() {
+        assert_eq!(write_f80(0.0), f80(0, 0));
+        assert_eq!(write_f80(-0.0), f80(0x8000, 0));
+        assert_eq!(write_f80(1.0), f80(16383, 1 << 63));
+        assert_eq!(write_f80(44100.0), f80(16398, 0xac44_0000_0000_0000));
+        assert_eq!(write_f80(f64::from_bits(1)), f80(MIN_DENORMAL, 1 << 63));
+        assert_eq!(write_f80(f64::NEG_INFINITY), f80(0xffff, 1 << 63));
+    }
+
+    #[test]
+    fn round_trip() {
+        let values = [
+            0.0,
+            -0.0,
+            1.0,
+            -1.5,
+            22050.0,
+            std::f64::consts::PI,
+            1e300,
+            -1e-300,
+            f64::MAX,
+            f64::MIN,
+            f64::MIN_POSITIVE,
+            f64::from_bits(1),
+            f64::from_bits(0x000f_ffff_ffff_ffff),
+            -f64::from_bits(0x0000_0000_dead_beef),
+            f64::INFINITY,
+            f64::NEG_INFINITY,
+        ];
+        for &value in &values {
+            let read = read_f80(&write_f80(value)).unwrap();
+            assert_eq!(read.to_bits(), value.to_bits(), "{:e}", value);
+        }
+        assert!(read_f80(&write_f80(f64::NAN)).unwrap().is_nan());
+    }
+}
@@ -0,0 +1,101 @@
+//! Dealing with files that break the spec in small ways.
+
+use crate::error::*;
+use crate::types::ID;
+use std::fmt;
+use thiserror::Error;
+
+/// How strictly files are checked against the spec.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ParseMode {
+    /// Every violation is an error, and they're all reported at once.
+    Strict,
+    /// Violations that don't get in the way of reading the file are ignored, the others are
+    /// errors.
+    #[default]
+    Normal,
+    /// Violations are repaired where possible, and kept as warnings.
+    Lenient,
+}
+
+/// A way a file breaks the spec.
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum Violation {
+    #[error("FORM chunk ends at {declared}, but the file is {actual} bytes long")]
+    FormSize { declared: u64, actual: u64 },
+    #[error("{size} trailing bytes at offset {offset} are too short to be a chunk")]
+    TrailingData { offset: u64, size: u64 },
+    #[error("{id} chunk at offset {offset} runs {excess} bytes past the end of the FORM chunk")]
+    ChunkPastEnd { id: ID, offset: u64, excess: u64 },
+    #[error("{id} chunk at offset {offset} has an odd size but no padding byte")]
+    MissingPadding { id: ID, offset: u64 },
+    #[error("common chunk at offset {offset} is {size} bytes long instead of 18")]
+    CommonChunkSize { offset: u64, size: u32 },
+    #[error("common chunk declares {declared} sample frames, but the sound data holds {actual}")]
+    SampleFrameCount { declared: u32, actual: u32 },
+}
+
+/// Every violation found in a file, as reported in strict mode.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Violations(pub Vec<Violation>);
+
+impl fmt::Display for Violations {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, violation) in self.0.iter().enumerate() {
+            if i > 0 {
+                write!(f, "; ")?;
+            }
+            write!(f, "{}", violation)?;
+        }
+        Ok(())
+    }
+}
+
+/// Collects violations according to the parse mode.
+#[derive(Debug)]
+pub(crate) struct Validator {
+    mode: ParseMode,
+    violations: Vec<Violation>,
+}
+
+impl Validator {
+    pub(crate) fn new(mode: ParseMode) -> Self {
+        Self {
+            mode,
+            violations: Vec::new(),
+        }
+    }
+
+    /// Whether violations are collected and repaired, rather than ignored or failed on. This is
+    /// also the case in strict mode, so that parsing goes on to find the other violations, and
+    /// only `finish` fails.
+    pub(crate) fn collects(&self) -> bool {
+        self.mode != ParseMode::Normal
+    }
+
+    /// Reports a violation that is ignored in normal mode.
+    pub(crate) fn warn(&mut self, violation: Violation) {
+        if self.collects() {
+            self.violations.push(violation);
+        }
+    }
+
+    /// Reports a violation that is the given error in normal mode.
+    pub(crate) fn fail(&mut self, violation: Violation, error: AiffError) -> Result<()> {
+        if self.collects() {
+            self.violations.push(violation);
+            Ok(())
+        } else {
+            Err(error)
+        }
+    }
+
+    /// Gives the warnings, or fails with every violation in strict mode.
+    pub(crate) fn finish(self) -> Result<Vec<Violation>> {
+        if self.mode == ParseMode::Strict && !self.violations.is_empty() {
+            Err(AiffError::Violations(Violations(self.violations)))
+        } else {
+            Ok(self.violations)
+        }
+    }
+}
@@ -1,37 +1,152 @@
+use aiff::chunks::inst::Loop;
+use aiff::{AiffStream, LoopedFrames, Pitched, Sample};
 use cpal::traits::*;
-use std::io::Read;
+use std::io::{Cursor, Read, Seek};
 use std::sync::mpsc;
 
 const SAMPLE_RATE: u32 = 44_100;
 
+/// How long a release loop plays for, in seconds, fading out.
+const RELEASE_TIME: f64 = 2.0;
+
+struct Options {
+    /// How long to hold the note for, in seconds, if the instrument's loops are to be played.
+    hold: Option<f64>,
+    /// MIDI note to play the instrument at.
+    note: Option<u8>,
+    velocity: u8,
+    path: Option<String>,
+}
+
+fn parse_args() -> Options {
+    let mut options = Options {
+        hold: None,
+        note: None,
+        velocity: 127,
+        path: None,
+    };
+
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--hold" => {
+                let hold = args.next().and_then(|value| value.parse().ok());
+                options.hold = Some(hold.unwrap_or_else(|| usage()));
+            }
+            "--note" => {
+                let note = args.next().and_then(|value| value.parse().ok());
+                options.note = Some(note.unwrap_or_else(|| usage()));
+            }
+            "--velocity" => {
+                let velocity = args.next().and_then(|value| value.parse().ok());
+                options.velocity = velocity.unwrap_or_else(|| usage());
+            }
+            _ if options.path.is_none() && !arg.starts_with("--") => options.path = Some(arg),
+            _ => usage(),
+        }
+    }
+    options
+}
+
+fn usage() -> ! {
+    eprintln!(
+        "usage: aiffplay [--hold <seconds>] [--note <MIDI note> [--velocity <velocity>]] [AIFF file]"
+    );
+    std::process::exit(1);
+}
+
 fn main() {
-    let mut data = Vec::new();
-    std::io::stdin().read_to_end(&mut data).unwrap();
-    let aiff = aiff::AiffReader {
-        ..Default::default()
+    let options = parse_args();
+
+    // files are streamed, but standard input can't be seeked through
+    match &options.path {
+        Some(path) => play(std::fs::File::open(path).unwrap(), &options),
+        None => {
+            let mut data = Vec::new();
+            std::io::stdin().read_to_end(&mut data).unwrap();
+            play(Cursor::new(data), &options);
+        }
     }
-    .read(&data)
-    .unwrap();
+}
+
+fn play<R: Read + Seek>(reader: R, options: &Options) {
+    let mut aiff = AiffStream::new(reader).unwrap();
+    let sample_rate = aiff.comm().sample_rate;
+
+    let mark = aiff.mark().unwrap();
+    let inst = aiff.inst().unwrap();
+
+    let note = options.note.map(|note| {
+        let inst = inst.as_ref().unwrap_or_else(|| {
+            eprintln!("the file has no instrument chunk to play notes with");
+            std::process::exit(1);
+        });
+        inst.note(note, options.velocity).unwrap_or_else(|err| {
+            eprintln!("{}", err);
+            std::process::exit(1);
+        })
+    });
+    // the rate samples are consumed at
+    let playback_rate = sample_rate * note.map_or(1.0, |note| note.pitch_ratio);
+
+    let frames: Vec<Vec<f32>> = match options.hold {
+        Some(hold) => {
+            let resolve = |instrument_loop: &Loop| instrument_loop.resolve(mark.as_ref()?);
+            let sustain_loop = inst.as_ref().and_then(|inst| resolve(&inst.sustain_loop));
+            let release_loop = inst.as_ref().and_then(|inst| resolve(&inst.release_loop));
+
+            let mut looped = aiff
+                .samples()
+                .unwrap()
+                .frames()
+                .looped(sustain_loop, release_loop)
+                .unwrap();
+            play_looped(&mut looped, hold, playback_rate)
+        }
+        None => aiff
+            .samples()
+            .unwrap()
+            .frames()
+            .collect::<aiff::Result<_>>()
+            .unwrap(),
+    };
+    let frames = match note {
+        Some(note) => Pitched::new(frames.into_iter(), note).collect(),
+        None => frames,
+    };
+
+    // anything past stereo is downmixed, as output devices may not support it
+    let num_channels = aiff.comm().num_channels as usize;
+    let mut channels = vec![Vec::with_capacity(frames.len()); num_channels.min(2)];
+    for frame in frames {
+        if num_channels <= 2 {
+            for (channel, sample) in channels.iter_mut().zip(frame) {
+                channel.push(sample);
+            }
+        } else {
+            channels[0].push(f32::average(&frame));
+        }
+    }
+    let channels: Vec<Vec<f32>> = channels
+        .into_iter()
+        .map(|channel| resample(channel, sample_rate))
+        .collect();
+    let num_channels = channels.len();
+    let samples: Vec<f32> = (0..channels[0].len())
+        .flat_map(|i| channels.iter().map(move |channel| channel[i]))
+        .collect();
 
     let (finished_send, finished_recv) = mpsc::sync_channel::<()>(0);
 
     let host = cpal::default_host();
     let device = host.default_output_device().unwrap();
     let stream = {
-        use dasp::signal::Signal;
-
-        let mut samples = dasp::signal::from_iter(aiff.samples().normalize_to_f32());
-        let interp = dasp::interpolate::linear::Linear::new(samples.next(), samples.next());
-        let samples: Vec<_> = samples
-            .from_hz_to_hz(interp, aiff.comm.sample_rate as f64, SAMPLE_RATE as f64)
-            .until_exhausted()
-            .collect();
         let mut i = 0;
 
         device
             .build_output_stream(
                 &cpal::StreamConfig {
-                    channels: 1,
+                    channels: num_channels as u16,
                     sample_rate: cpal::SampleRate(SAMPLE_RATE),
                     buffer_size: cpal::BufferSize::Default,
                 },
@@ -55,3 +170,35 @@ fn main() {
     finished_recv.recv().unwrap();
     std::process::exit(0);
 }
+
+/// Holds the note for `hold` seconds, then releases it. A release loop is faded out.
+fn play_looped(looped: &mut LoopedFrames<f32>, hold: f64, playback_rate: f64) -> Vec<Vec<f32>> {
+    let mut frames: Vec<Vec<f32>> = looped
+        .by_ref()
+        .take((hold * playback_rate) as usize)
+        .collect();
+    looped.release();
+
+    let release_frames = (RELEASE_TIME * playback_rate) as usize;
+    let release: Vec<Vec<f32>> = looped.by_ref().take(release_frames).collect();
+    let fade = looped.next().is_some();
+    for (i, mut frame) in release.into_iter().enumerate() {
+        if fade {
+            let gain = 1.0 - i as f32 / release_frames as f32;
+            frame.iter_mut().for_each(|sample| *sample *= gain);
+        }
+        frames.push(frame);
+    }
+    frames
+}
+
+fn resample(samples: Vec<f32>, sample_rate: f64) -> Vec<f32> {
+    use dasp::signal::Signal;
+
+    let mut samples = dasp::signal::from_iter(samples);
+    let interp = dasp::interpolate::linear::Linear::new(samples.next(), samples.next());
+    samples
+        .from_hz_to_hz(interp, sample_rate, SAMPLE_RATE as f64)
+        .until_exhausted()
+        .collect()
+}
@@ -0,0 +1,600 @@
+//! Instrument bank (`.ctl`) parsing, referenced from the game's `AudioBankSample`, `Instrument`
+//! and `Drum` structures.
+//!
+//! A bank's pointers are offsets relative to the start of its body (i.e. after its 16-byte
+//! header), except for sample addresses, which are offsets into the bank's sample table.
+//! Samples and envelopes are shared between instruments, so they're deduplicated and referred
+//! to by their index.
+
+use crate::error::*;
+use crate::state::ChannelInstrument;
+use byteorder::{ByteOrder, BE};
+use std::collections::HashMap;
+use vadpcm::{Codebook, Decoder, FrameFormat};
+
+fn slice(data: &[u8], offset: usize, len: usize) -> Result<&[u8]> {
+    data.get(offset..)
+        .and_then(|data| data.get(..len))
+        .ok_or(BankError::OutOfBounds { offset, len })
+}
+
+/// A whole `.ctl` file, holding several banks.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Ctl {
+    pub revision: u16,
+    pub banks: Vec<Bank>,
+}
+
+impl Ctl {
+    pub fn read(data: &[u8]) -> Result<Self> {
+        let (revision, entries) = read_seq_file(data)?;
+        let banks = entries
+            .iter()
+            .map(|&(offset, len)| Bank::read(slice(data, offset, len)?))
+            .collect::<Result<_>>()?;
+
+        Ok(Self { revision, banks })
+    }
+
+    pub fn bank(&self, id: usize) -> Result<&Bank> {
+        self.banks.get(id).ok_or(BankError::MissingBank(id))
+    }
+}
+
+/// Reads an `ALSeqFile` header, as used by both `.ctl` and `.tbl` files. Returns the revision,
+/// and the offset and length of every entry.
+pub(crate) fn read_seq_file(data: &[u8]) -> Result<(u16, Vec<(usize, usize)>)> {
+    let header = slice(data, 0, 4)?;
+    let revision = BE::read_u16(&header[0..2]);
+    let count = BE::read_u16(&header[2..4]) as usize;
+
+    let entries = slice(data, 4, count * 8)?
+        .chunks(8)
+        .map(|entry| {
+            (
+                BE::read_u32(&entry[0..4]) as usize,
+                BE::read_u32(&entry[4..8]) as usize,
+            )
+        })
+        .collect();
+
+    Ok((revision, entries))
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Bank {
+    /// Nonzero if the bank's sample table is shared with other banks.
+    pub shared: u32,
+    /// Date of creation, as BCD (e.g. 0x19960603).
+    pub date: u32,
+    /// Indexed by instrument ID; empty slots are `None`.
+    pub instruments: Vec<Option<Instrument>>,
+    /// Indexed by semitone; empty slots are `None`.
+    pub drums: Vec<Option<Drum>>,
+    pub samples: Vec<Sample>,
+    pub envelopes: Vec<Envelope>,
+}
+
+impl Bank {
+    const HEADER_SIZE: usize = 16;
+
+    /// Reads a single bank, header included.
+    pub fn read(data: &[u8]) -> Result<Self> {
+        let header = slice(data, 0, Self::HEADER_SIZE)?;
+        let num_instruments = BE::read_u32(&header[0..4]) as usize;
+        let num_drums = BE::read_u32(&header[4..8]) as usize;
+        let shared = BE::read_u32(&header[8..12]);
+        let date = BE::read_u32(&header[12..16]);
+
+        let mut reader = BankReader {
+            data: &data[Self::HEADER_SIZE..],
+            samples: Vec::new(),
+            sample_indices: HashMap::new(),
+            envelopes: Vec::new(),
+            envelope_indices: HashMap::new(),
+        };
+
+        let drums_offset = BE::read_u32(slice(reader.data, 0, 4)?) as usize;
+        let instrument_offsets = slice(reader.data, 4, num_instruments * 4)?;
+
+        let instruments = instrument_offsets
+            .chunks(4)
+            .map(|offset| match BE::read_u32(offset) {
+                0 => Ok(None),
+                offset => reader.read_instrument(offset).map(Some),
+            })
+            .collect::<Result<_>>()?;
+
+        let drums = if num_drums == 0 {
+            Vec::new()
+        } else {
+            slice(reader.data, drums_offset, num_drums * 4)?
+                .chunks(4)
+                .map(|offset| match BE::read_u32(offset) {
+                    0 => Ok(None),
+                    offset => reader.read_drum(offset).map(Some),
+                })
+                .collect::<Result<_>>()?
+        };
+
+        Ok(Self {
+            shared,
+            date,
+            instruments,
+            drums,
+            samples: reader.samples,
+            envelopes: reader.envelopes,
+        })
+    }
+
+    /// The instrument selected by `SetInstr` with the given ID, if any.
+    pub fn instrument(&self, id: u8) -> Option<&Instrument> {
+        self.instruments.get(id as usize)?.as_ref()
+    }
+
+    /// The drum played by a note with the given semitone, if any.
+    pub fn drum(&self, semitone: u8) -> Option<&Drum> {
+        self.drums.get(semitone as usize)?.as_ref()
+    }
+
+    /// Resolves a note played by a channel with the given instrument to the sound it plays.
+    /// Raw waves aren't part of banks, so they resolve to nothing.
+    pub fn sound(&self, instrument: ChannelInstrument, semitone: u8) -> Option<&Sound> {
+        match instrument {
+            ChannelInstrument::Instrument(id) => self.instrument(id)?.sound(semitone),
+            ChannelInstrument::Drums => self.drum(semitone)?.sound.as_ref(),
+            ChannelInstrument::Wave(_) | ChannelInstrument::None => None,
+        }
+    }
+
+    pub fn sample(&self, sound: &Sound) -> &Sample {
+        &self.samples[sound.sample]
+    }
+
+    pub fn envelope(&self, index: usize) -> &Envelope {
+        &self.envelopes[index]
+    }
+}
+
+struct BankReader<'a> {
+    data: &'a [u8],
+    samples: Vec<Sample>,
+    sample_indices: HashMap<u32, usize>,
+    envelopes: Vec<Envelope>,
+    envelope_indices: HashMap<u32, usize>,
+}
+
+impl<'a> BankReader<'a> {
+    fn read_instrument(&mut self, offset: u32) -> Result<Instrument> {
+        let data = slice(self.data, offset as usize, Instrument::SIZE)?;
+
+        let normal_range_lo = data[1];
+        let normal_range_hi = data[2];
+        if normal_range_lo > normal_range_hi {
+            return Err(BankError::InvalidNoteRange(
+                normal_range_lo,
+                normal_range_hi,
+            ));
+        }
+
+        Ok(Instrument {
+            normal_range_lo,
+            normal_range_hi,
+            release_rate: data[3],
+            envelope: self.read_envelope(BE::read_u32(&data[4..8]))?,
+            low_notes_sound: self.read_sound(&data[8..16])?,
+            normal_notes_sound: self.read_sound(&data[16..24])?,
+            high_notes_sound: self.read_sound(&data[24..32])?,
+        })
+    }
+
+    fn read_drum(&mut self, offset: u32) -> Result<Drum> {
+        let data = slice(self.data, offset as usize, Drum::SIZE)?;
+
+        Ok(Drum {
+            release_rate: data[0],
+            pan: data[1],
+            sound: self.read_sound(&data[4..12])?,
+            envelope: self.read_envelope(BE::read_u32(&data[12..16]))?,
+        })
+    }
+
+    fn read_sound(&mut self, data: &[u8]) -> Result<Option<Sound>> {
+        let sample_offset = BE::read_u32(&data[0..4]);
+        if sample_offset == 0 {
+            return Ok(None);
+        }
+
+        Ok(Some(Sound {
+            sample: self.read_sample(sample_offset)?,
+            tuning: BE::read_f32(&data[4..8]),
+        }))
+    }
+
+    fn read_sample(&mut self, offset: u32) -> Result<usize> {
+        if let Some(&index) = self.sample_indices.get(&offset) {
+            return Ok(index);
+        }
+
+        let data = slice(self.data, offset as usize, Sample::SIZE)?;
+        let sample = Sample {
+            addr: BE::read_u32(&data[4..8]),
+            adpcm_loop: AdpcmLoop::read(self.data, BE::read_u32(&data[8..12]))?,
+            book: AdpcmBook::read(self.data, BE::read_u32(&data[12..16]))?,
+            size: BE::read_u32(&data[16..20]),
+        };
+
+        let index = self.samples.len();
+        self.samples.push(sample);
+        self.sample_indices.insert(offset, index);
+        Ok(index)
+    }
+
+    fn read_envelope(&mut self, offset: u32) -> Result<usize> {
+        if let Some(&index) = self.envelope_indices.get(&offset) {
+            return Ok(index);
+        }
+
+        let envelope = Envelope::read(self.data, offset)?;
+
+        let index = self.envelopes.len();
+        self.envelopes.push(envelope);
+        self.envelope_indices.insert(offset, index);
+        Ok(index)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Instrument {
+    /// Notes below this semitone use `low_notes_sound`.
+    pub normal_range_lo: u8,
+    /// Notes above this semitone use `high_notes_sound`.
+    pub normal_range_hi: u8,
+    pub release_rate: u8,
+    /// Index into the bank's envelopes.
+    pub envelope: usize,
+    pub low_notes_sound: Option<Sound>,
+    /// Can be missing in otherwise valid banks, in which case those notes don't play.
+    pub normal_notes_sound: Option<Sound>,
+    pub high_notes_sound: Option<Sound>,
+}
+
+impl Instrument {
+    const SIZE: usize = 0x20;
+
+    // ported from instrument_get_audio_bank_sound
+    /// The sound used for a note with the given semitone. Notes without a sound for their
+    /// range can't be played.
+    pub fn sound(&self, semitone: u8) -> Option<&Sound> {
+        if semitone < self.normal_range_lo {
+            self.low_notes_sound.as_ref()
+        } else if semitone <= self.normal_range_hi {
+            self.normal_notes_sound.as_ref()
+        } else {
+            self.high_notes_sound.as_ref()
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Drum {
+    pub release_rate: u8,
+    /// 0…128, from left to right.
+    pub pan: u8,
+    /// Can be missing in otherwise valid banks, in which case the drum doesn't play.
+    pub sound: Option<Sound>,
+    /// Index into the bank's envelopes.
+    pub envelope: usize,
+}
+
+impl Drum {
+    const SIZE: usize = 0x10;
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Sound {
+    /// Index into the bank's samples.
+    pub sample: usize,
+    /// Playback rate multiplier, relative to the output frequency, for a note with semitone 39.
+    pub tuning: f32,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Sample {
+    /// Offset of the sample data within the bank's sample table.
+    pub addr: u32,
+    /// Size of the sample data in bytes. Not used by the game.
+    pub size: u32,
+    pub adpcm_loop: AdpcmLoop,
+    pub book: AdpcmBook,
+}
+
+impl Sample {
+    const SIZE: usize = 0x14;
+
+    /// The sample's (usually VADPCM-compressed) data, out of the sample table data of its bank.
+    pub fn data<'a>(&self, table_bank: &'a [u8]) -> Result<&'a [u8]> {
+        slice(table_bank, self.addr as usize, self.size as usize)
+    }
+
+    /// Decodes the sample, looping as many times as its loop says (possibly forever).
+    pub fn samples<'a>(&self, table_bank: &'a [u8]) -> Result<vadpcm::Samples<'a>> {
+        let decoder = Decoder::new(self.book.codebook()?, FrameFormat::Bits4);
+        let samples = decoder.samples(self.data(table_bank)?)?;
+        Ok(samples.with_loop(self.adpcm_loop.to_loop()))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct AdpcmLoop {
+    /// Loop start, in samples.
+    pub start: u32,
+    /// Loop end, in samples. For samples that don't loop, this is their length.
+    pub end: u32,
+    /// Amount of times to loop; 0 for none, and `u32::MAX` for an infinite loop.
+    pub count: u32,
+    /// Decoder state to restore when going back to the loop start. Only present if the
+    /// sample loops.
+    pub state: Option<[i16; 16]>,
+}
+
+impl AdpcmLoop {
+    const SIZE: usize = 0x10;
+    const STATE_SIZE: usize = 0x20;
+
+    pub fn to_loop(&self) -> vadpcm::Loop {
+        vadpcm::Loop {
+            start: self.start,
+            end: self.end,
+            count: self.count,
+            state: self.state.unwrap_or_default(),
+        }
+    }
+
+    fn read(data: &[u8], offset: u32) -> Result<Self> {
+        let header = slice(data, offset as usize, Self::SIZE)?;
+        let start = BE::read_u32(&header[0..4]);
+        let end = BE::read_u32(&header[4..8]);
+        let count = BE::read_u32(&header[8..12]);
+        if start > end {
+            return Err(BankError::InvalidLoop { start, end });
+        }
+
+        let state = if count != 0 {
+            let data = slice(data, offset as usize + Self::SIZE, Self::STATE_SIZE)?;
+            let mut state = [0; 16];
+            BE::read_i16_into(data, &mut state);
+            Some(state)
+        } else {
+            None
+        };
+
+        Ok(Self {
+            start,
+            end,
+            count,
+            state,
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct AdpcmBook {
+    pub order: u32,
+    pub npredictors: u32,
+    /// `npredictors` predictors, each made of `order` rows of 8 coefficients.
+    pub book: Vec<i16>,
+}
+
+impl AdpcmBook {
+    const HEADER_SIZE: usize = 8;
+
+    pub fn codebook(&self) -> Result<Codebook> {
+        Ok(Codebook::new(
+            self.order as usize,
+            self.npredictors as usize,
+            self.book.clone(),
+        )?)
+    }
+
+    fn read(data: &[u8], offset: u32) -> Result<Self> {
+        let header = slice(data, offset as usize, Self::HEADER_SIZE)?;
+        let order = BE::read_u32(&header[0..4]);
+        let npredictors = BE::read_u32(&header[4..8]);
+        // the predictor index is stored as 4 bits in every frame
+        if order == 0 || order > 8 || npredictors == 0 || npredictors > 16 {
+            return Err(BankError::InvalidBook { order, npredictors });
+        }
+
+        let len = 8 * order as usize * npredictors as usize;
+        let data = slice(data, offset as usize + Self::HEADER_SIZE, len * 2)?;
+        let mut book = vec![0; len];
+        BE::read_i16_into(data, &mut book);
+
+        Ok(Self {
+            order,
+            npredictors,
+            book,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EnvelopeCmd {
+    /// Go linearly to the given volume over some amount of ticks.
+    Point { delay: u16, volume: i16 },
+    /// Stop the note.
+    Disable,
+    /// Stay at the current volume forever.
+    Hang,
+    /// Go to the given point of the envelope.
+    Goto(u16),
+    /// Go back to the start of the envelope.
+    Restart,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Envelope {
+    /// Always ends with a command other than `Point`.
+    pub cmds: Vec<EnvelopeCmd>,
+}
+
+impl Envelope {
+    fn read(data: &[u8], offset: u32) -> Result<Self> {
+        let mut cmds = Vec::new();
+        let mut data = data
+            .get(offset as usize..)
+            .ok_or(BankError::UnterminatedEnvelope(offset))?;
+
+        loop {
+            if data.len() < 4 {
+                return Err(BankError::UnterminatedEnvelope(offset));
+            }
+            let delay = BE::read_i16(&data[0..2]);
+            let arg = BE::read_i16(&data[2..4]);
+            data = &data[4..];
+
+            let cmd = match delay {
+                0 => EnvelopeCmd::Disable,
+                -1 => EnvelopeCmd::Hang,
+                -2 => EnvelopeCmd::Goto(arg as u16),
+                -3 => EnvelopeCmd::Restart,
+                _ => EnvelopeCmd::Point {
+                    delay: delay as u16,
+                    volume: arg,
+                },
+            };
+            cmds.push(cmd);
+
+            if !matches!(cmd, EnvelopeCmd::Point { .. }) {
+                break;
+            }
+        }
+
+        Ok(Self { cmds })
+    }
+}
+
+/// A whole `.tbl` file, holding the sample data of every bank.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SampleTable<'a> {
+    pub revision: u16,
+    /// Indexed by bank ID. Banks sharing their samples point to the same data.
+    pub banks: Vec<&'a [u8]>,
+}
+
+impl<'a> SampleTable<'a> {
+    pub fn read(data: &'a [u8]) -> Result<Self> {
+        let (revision, entries) = read_seq_file(data)?;
+        let banks = entries
+            .iter()
+            .map(|&(offset, len)| slice(data, offset, len))
+            .collect::<Result<_>>()?;
+
+        Ok(Self { revision, banks })
+    }
+
+    /// The sample data used by the bank with the given ID.
+    pub fn bank(&self, id: usize) -> Result<&'a [u8]> {
+        self.banks
+            .get(id)
+            .copied()
+            .ok_or(BankError::MissingBank(id))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const INSTRUMENT: usize = Bank::HEADER_SIZE + 0x10;
+    const DRUM: usize = Bank::HEADER_SIZE + 0x30;
+
+    /// A bank with an instrument and a drum sharing a sample and an envelope.
+    fn bank() -> Vec<u8> {
+        #[rustfmt::skip]
+        let words: &[u32] = &[
+            // header: 1 instrument, 1 drum, not shared, date
+            1, 1, 0, 0x1996_0603,
+            // 0x00: drums, instrument 0, drum 0, padding
+            0x08, 0x10, 0x30, 0,
+            // 0x10: instrument with a range of 0x10..=0x60, envelope and sounds
+            0x0010_6080, 0x40, 0x50, 0x3f80_0000, 0x50, 0x4000_0000, 0, 0,
+            // 0x30: drum with a release rate, pan, sound and envelope
+            0x8040_0000, 0x50, 0x3f80_0000, 0x40,
+            // 0x40: envelope, then padding
+            0x0002_7fff, 0xffff_0000, 0, 0,
+            // 0x50: sample, then padding
+            0, 0, 0x68, 0x78, 0x90, 0,
+            // 0x68: loop without a state
+            0, 0x10, 0, 0,
+            // 0x78: codebook header, then an order 2 predictor
+            2, 1, 0, 0, 0, 0, 0, 0, 0, 0,
+        ];
+        let mut data = vec![0; words.len() * 4];
+        BE::write_u32_into(words, &mut data);
+        data
+    }
+
+    #[test]
+    fn valid() {
+        let bank = Bank::read(&bank()).unwrap();
+        assert_eq!(bank.date, 0x1996_0603);
+        assert_eq!(bank.samples.len(), 1);
+        assert_eq!(bank.samples[0].size, 0x90);
+        assert_eq!(bank.samples[0].adpcm_loop.end, 0x10);
+        assert_eq!(bank.samples[0].book.book, vec![0; 16]);
+        assert_eq!(
+            bank.envelopes,
+            vec![Envelope {
+                cmds: vec![
+                    EnvelopeCmd::Point {
+                        delay: 2,
+                        volume: 0x7fff
+                    },
+                    EnvelopeCmd::Hang
+                ]
+            }]
+        );
+
+        let instrument = bank.instrument(0).unwrap();
+        assert_eq!(instrument.release_rate, 0x80);
+        assert_eq!(instrument.sound(0x0f).unwrap().tuning, 1.0);
+        assert_eq!(instrument.sound(0x60).unwrap().tuning, 2.0);
+        assert_eq!(instrument.sound(0x61), None);
+        assert_eq!(bank.drum(0).unwrap().pan, 0x40);
+        assert_eq!(bank.drum(1), None);
+    }
+
+    #[test]
+    fn truncated() {
+        let data = bank();
+        for len in [8, Bank::HEADER_SIZE + 4, data.len() - 2] {
+            assert!(matches!(
+                Bank::read(&data[..len]),
+                Err(BankError::OutOfBounds { .. })
+            ));
+        }
+    }
+
+    #[test]
+    fn invalid_note_range() {
+        let mut data = bank();
+        data[INSTRUMENT + 1] = 0x70;
+        assert!(matches!(
+            Bank::read(&data),
+            Err(BankError::InvalidNoteRange(0x70, 0x60))
+        ));
+    }
+
+    #[test]
+    fn missing_sounds() {
+        let mut data = bank();
+        data[INSTRUMENT + 16..INSTRUMENT + 20].fill(0);
+        data[DRUM + 4..DRUM + 8].fill(0);
+        let bank = Bank::read(&data).unwrap();
+        assert_eq!(bank.instrument(0).unwrap().sound(0x30), None);
+        assert_eq!(bank.sound(ChannelInstrument::Drums, 0), None);
+        assert_eq!(bank.samples.len(), 1);
+    }
+}
@@ -0,0 +1,208 @@
+//! Dumps every sample of a bank to an AIFC file, keeping its codebook, loop and tuning. With
+//! `--pcm`, samples are decoded and written as uncompressed AIFF files instead.
+
+use aiff::chunks::comm::{CommonChunk, Compression};
+use aiff::chunks::fver::FormatVersionChunk;
+use aiff::chunks::inst::{InstrumentChunk, Loop, PlayMode};
+use aiff::chunks::mark::{Marker, MarkerChunk};
+use aiff::chunks::ssnd::SoundDataChunk;
+use aiff::chunks::vadpcm::{VadpcmCodesChunk, VadpcmLoopsChunk};
+use aiff::{Aiff, FormType};
+use m64::bank::{Bank, Ctl, Sample, SampleTable};
+use std::error::Error;
+use std::io::Write;
+use std::path::Path;
+use vadpcm::{Decoder, FrameFormat};
+
+/// The output frequency tunings are relative to.
+const TUNING_FREQUENCY: f64 = 32000.0;
+/// A tuning of 1.0 plays the sample as is for semitone 39, which is MIDI's middle C.
+const BASE_NOTE: i8 = 60;
+
+fn main() {
+    let mut args: Vec<String> = std::env::args().collect();
+    let pcm = args.iter().any(|arg| arg == "--pcm");
+    args.retain(|arg| arg != "--pcm");
+    if args.len() != 5 {
+        eprintln!(
+            "usage: {} [--pcm] <ctl file> <tbl file> <bank id> <output dir>",
+            args[0]
+        );
+        std::process::exit(1);
+    }
+
+    let bank_id: usize = args[3].parse().expect("invalid bank id");
+    if let Err(err) = dump_bank(&args[1], &args[2], bank_id, Path::new(&args[4]), pcm) {
+        eprintln!("error: {}", err);
+        std::process::exit(1);
+    }
+}
+
+/// Dumps every sample of the bank. Samples that can't be dumped are reported and skipped.
+fn dump_bank(
+    ctl_path: &str,
+    tbl_path: &str,
+    bank_id: usize,
+    output_dir: &Path,
+    pcm: bool,
+) -> Result<(), Box<dyn Error>> {
+    let ctl_data = std::fs::read(ctl_path)?;
+    let tbl_data = std::fs::read(tbl_path)?;
+
+    let ctl = Ctl::read(&ctl_data)?;
+    let table = SampleTable::read(&tbl_data)?;
+    let bank = ctl.bank(bank_id)?;
+    let table_bank = table.bank(bank_id)?;
+
+    std::fs::create_dir_all(output_dir)?;
+    for i in 0..bank.samples.len() {
+        if let Err(err) = dump_sample(bank, i, table_bank, output_dir, pcm) {
+            eprintln!("error: sample {}: {}", i, err);
+        }
+    }
+    Ok(())
+}
+
+fn dump_sample(
+    bank: &Bank,
+    i: usize,
+    table_bank: &[u8],
+    output_dir: &Path,
+    pcm: bool,
+) -> Result<(), Box<dyn Error>> {
+    let sample = &bank.samples[i];
+    let tuning = sample_tuning(bank, i).unwrap_or(1.0);
+    let data = sample.data(table_bank)?;
+
+    let pcm_samples = if pcm {
+        let book = sample.book.codebook()?;
+        let mut samples = Decoder::new(book, FrameFormat::Bits4).decode(data)?;
+        samples.truncate(sample.adpcm_loop.end as usize);
+        Some(samples)
+    } else {
+        None
+    };
+
+    let extension = if pcm { "aiff" } else { "aifc" };
+    let path = output_dir.join(format!("sample_{}.{}", i, extension));
+    let mut file = std::fs::File::create(&path)?;
+    write_aifc(&mut file, sample, data, tuning, pcm_samples.as_deref())?;
+    println!(
+        "{}: tuning {}, {:?}",
+        path.display(),
+        tuning,
+        sample.adpcm_loop
+    );
+    Ok(())
+}
+
+/// Samples may be shared between sounds with different tunings, so this picks the first one.
+fn sample_tuning(bank: &Bank, sample: usize) -> Option<f32> {
+    let instrument_sounds = bank.instruments.iter().flatten().flat_map(|instrument| {
+        instrument
+            .normal_notes_sound
+            .iter()
+            .chain(instrument.low_notes_sound.as_ref())
+            .chain(instrument.high_notes_sound.as_ref())
+    });
+    let drum_sounds = bank.drums.iter().flatten().flat_map(|drum| &drum.sound);
+
+    instrument_sounds
+        .chain(drum_sounds)
+        .find(|sound| sound.sample == sample)
+        .map(|sound| sound.tuning)
+}
+
+fn write_aifc(
+    w: &mut impl Write,
+    sample: &Sample,
+    data: &[u8],
+    tuning: f32,
+    pcm: Option<&[i16]>,
+) -> Result<(), Box<dyn Error>> {
+    let has_loop = sample.adpcm_loop.count != 0;
+
+    let pcm_data: Option<Vec<u8>> = pcm.map(|samples| {
+        samples
+            .iter()
+            .flat_map(|s| s.to_be_bytes().to_vec())
+            .collect()
+    });
+    let (form_type, compression, num_sample_frames, ssnd) = match &pcm_data {
+        Some(pcm_data) => (FormType::Aiff, None, pcm_data.len() / 2, &pcm_data[..]),
+        None => (
+            FormType::Aifc,
+            Some(Compression::vadpcm()),
+            data.len() / 9 * 16,
+            data,
+        ),
+    };
+
+    let comm = CommonChunk {
+        num_channels: 1,
+        num_sample_frames: num_sample_frames as u32,
+        sample_size: 16,
+        sample_rate: tuning as f64 * TUNING_FREQUENCY,
+        compression,
+    };
+    let mut file = Aiff::new(form_type, comm, SoundDataChunk::new(ssnd));
+    if form_type == FormType::Aifc {
+        file.fver = Some(FormatVersionChunk::default());
+    }
+
+    let no_loop = || Loop {
+        play_mode: PlayMode::NoLooping,
+        begin_loop: 0,
+        end_loop: 0,
+    };
+    file.inst = Some(InstrumentChunk {
+        base_note: BASE_NOTE,
+        detune: 0,
+        low_note: 0,
+        high_note: 127,
+        low_velocity: 0,
+        high_velocity: 127,
+        gain: 0,
+        sustain_loop: if has_loop {
+            // forward looping between markers 1 and 2
+            Loop {
+                play_mode: PlayMode::ForwardLooping,
+                begin_loop: 1,
+                end_loop: 2,
+            }
+        } else {
+            no_loop()
+        },
+        release_loop: no_loop(),
+    });
+
+    if has_loop {
+        let marker = |id, position, name: &str| Marker {
+            id,
+            position,
+            name: name.to_string(),
+        };
+        file.mark = Some(MarkerChunk {
+            markers: vec![
+                marker(1, sample.adpcm_loop.start, "start"),
+                marker(2, sample.adpcm_loop.end, "end"),
+            ],
+        });
+    }
+
+    if pcm.is_none() {
+        file.vadpcm_codes = Some(VadpcmCodesChunk {
+            version: 1,
+            book: sample.book.codebook()?,
+        });
+        if sample.adpcm_loop.state.is_some() {
+            file.vadpcm_loops = Some(VadpcmLoopsChunk {
+                version: 1,
+                loops: vec![sample.adpcm_loop.to_loop()],
+            });
+        }
+    }
+
+    file.write(w)?;
+    Ok(())
+}
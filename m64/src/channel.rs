@@ -193,6 +193,9 @@ pub enum ChannelCmd {
     /// Set Q to 1 or 0, depending on whether layer N has been disabled (either
     /// forcibly or by finishing its script).
     TestLayerFinished(u8),
+
+    /// An opcode that isn't a command.
+    Unknown(u8),
 }
 
 impl ChannelCmd {
@@ -228,13 +231,13 @@ impl ChannelCmd {
 
             0xe4 => (DynCall, 1),
 
-            0xe3 => (SetVibratoDelay(data[1]), 1),
+            0xe3 => (SetVibratoDelay(data[1]), 2),
             0xe2 => (SetVibratoExtentLinear(data[1], data[2], data[3]), 4),
             0xe1 => (SetVibratoRateLinear(data[1], data[2], data[3]), 4),
             0xe0 => (SetVolScale(data[1]), 2),
 
             0xdf => (SetVol(data[1]), 2),
-            0xde => (FreqScale(BE::read_u16(&data[1..3])), 4),
+            0xde => (FreqScale(BE::read_u16(&data[1..3])), 3),
             0xdd => (SetPan(data[1]), 2),
             0xdc => (SetPanChanWeight(data[1]), 2),
 
@@ -286,7 +289,7 @@ impl ChannelCmd {
             0x10..=0x1f => (StartChannel(data[0] & 0x0f, BE::read_u16(&data[1..3])), 3),
             0x00..=0x0f => (TestLayerFinished(data[0] & 0x0f), 1),
 
-            _ => todo!("sequence command 0x{:02x}", data[0]),
+            opcode => (Unknown(opcode), 1),
         }
     }
 
@@ -0,0 +1,24 @@
+use thiserror::Error;
+use vadpcm::VadpcmError;
+
+#[derive(Error, Debug)]
+pub enum BankError {
+    #[error("{len} bytes at offset 0x{offset:x} are out of bounds")]
+    OutOfBounds { offset: usize, len: usize },
+    #[error("bank {0} does not exist")]
+    MissingBank(usize),
+
+    #[error("invalid note range {0}..={1}")]
+    InvalidNoteRange(u8, u8),
+    #[error("envelope at offset 0x{0:x} is not terminated")]
+    UnterminatedEnvelope(u32),
+    #[error("invalid codebook with order {order} and {npredictors} predictors")]
+    InvalidBook { order: u32, npredictors: u32 },
+    #[error("invalid loop from {start} to {end}")]
+    InvalidLoop { start: u32, end: u32 },
+
+    #[error("vadpcm error: {0}")]
+    Vadpcm(#[from] VadpcmError),
+}
+
+pub type Result<T> = std::result::Result<T, BankError>;
@@ -104,6 +104,9 @@ pub enum LayerCmd {
     /// and duration is set like in smallnote0. Only valid if channel
     /// is set to “small notes”.
     SmallNote2 { pitch: u8 },
+
+    /// An opcode that isn't a command.
+    Unknown(u8),
 }
 
 impl LayerCmd {
@@ -194,10 +197,10 @@ impl LayerCmd {
                             3,
                         ),
 
-                        _ => todo!("layer command 0x{:02x}", data[0]),
+                        opcode => (Unknown(opcode), 1),
                     }
                 } else {
-                    match data[1] {
+                    match data[0] {
                         0x00..=0x3f => {
                             let (var, size) = read_var(&data[1..3]);
                             (
@@ -221,7 +224,7 @@ impl LayerCmd {
                             1,
                         ),
 
-                        _ => todo!("layer command 0x{:02x}", data[0]),
+                        opcode => (Unknown(opcode), 1),
                     }
                 }
             }
@@ -1,10 +1,16 @@
 //! Referenced from https://hackmd.io/opEB-OmxRa26P8h8pA-x7w.
 
+mod error;
+pub use error::{BankError, Result};
+
+pub mod bank;
 pub mod channel;
 pub mod layer;
 pub mod sequence;
+pub mod session;
 
 pub mod state;
+pub mod synth;
 
 fn read_var(data: &[u8]) -> (u16, usize) {
     // check top bit of data[0]
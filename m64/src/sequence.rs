@@ -104,6 +104,9 @@ pub enum SequenceCmd {
 
     /// Set Q to 1 or 0, depending on whether channel N has been disabled by channel script.
     TestChDisabled(u8),
+
+    /// An opcode that isn't a command.
+    Unknown(u8),
 }
 
 impl SequenceCmd {
@@ -164,7 +167,7 @@ impl SequenceCmd {
             0x50..=0x5f => (SubVariation, 1),
             0x00..=0x0f => (TestChDisabled(data[0] & 0x0f), 1),
 
-            _ => todo!("sequence command 0x{:02x}", data[0]),
+            opcode => (Unknown(opcode), 1),
         }
     }
 
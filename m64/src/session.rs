@@ -0,0 +1,117 @@
+//! Audio session settings, ported from `AudioSessionSettings` and `gAudioSessionPresets`.
+//!
+//! Every level picks one of these presets when it sets its background music. They decide the
+//! output frequency and the number of notes that can play at once, and from those the amount of
+//! audio updates per frame (and thus the sequence timing) is derived.
+
+use crate::state::TATUMS_PER_BEAT;
+
+/// Video interface clock of an NTSC console, used to round the output frequency.
+const VI_CLOCK_NTSC: u32 = 48_681_812;
+const FRAMES_PER_SECOND: u32 = 60;
+
+// memory sizes shared by every vanilla preset
+const PERSISTENT_SEQ_MEM: u32 = 0x3a00;
+const PERSISTENT_BANK_MEM: u32 = 0x6200;
+const TEMPORARY_SEQ_MEM: u32 = 0x4400;
+const TEMPORARY_BANK_MEM: u32 = 0x2a00;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct AudioSessionSettings {
+    /// Requested output frequency in Hz. The hardware rounds it; see `ai_frequency`.
+    pub frequency: u32,
+    /// Amount of notes that can be played at once across all sequences.
+    pub max_simultaneous_notes: u8,
+    /// Always 1.
+    pub reverb_downsample_rate: u8,
+    pub reverb_window_size: u16,
+    pub reverb_gain: u16,
+    pub volume: u16,
+    pub persistent_seq_mem: u32,
+    pub persistent_bank_mem: u32,
+    pub temporary_seq_mem: u32,
+    pub temporary_bank_mem: u32,
+}
+
+const fn preset(
+    max_simultaneous_notes: u8,
+    reverb_window_size: u16,
+    reverb_gain: u16,
+) -> AudioSessionSettings {
+    AudioSessionSettings {
+        frequency: 32000,
+        max_simultaneous_notes,
+        reverb_downsample_rate: 1,
+        reverb_window_size,
+        reverb_gain,
+        volume: 0x7fff,
+        persistent_seq_mem: PERSISTENT_SEQ_MEM,
+        persistent_bank_mem: PERSISTENT_BANK_MEM,
+        temporary_seq_mem: TEMPORARY_SEQ_MEM,
+        temporary_bank_mem: TEMPORARY_BANK_MEM,
+    }
+}
+
+/// The presets built into the game (US version), indexed by the preset ID passed along with the
+/// level's background music.
+pub const PRESETS: [AudioSessionSettings; 18] = [
+    preset(16, 0x1000, 0x2fff),
+    preset(16, 0x0800, 0x2fff),
+    preset(16, 0x1000, 0x2fff),
+    preset(16, 0x1000, 0x2fff),
+    preset(16, 0x0c00, 0x2fff),
+    preset(16, 0x0a00, 0x47ff),
+    preset(16, 0x1000, 0x2fff),
+    preset(16, 0x1000, 0x2fff),
+    preset(16, 0x0e00, 0x2fff),
+    preset(16, 0x0c00, 0x2fff),
+    preset(16, 0x0800, 0x37ff),
+    preset(16, 0x0c00, 0x3fff),
+    preset(16, 0x1000, 0x2fff),
+    preset(16, 0x0800, 0x2fff),
+    preset(16, 0x1000, 0x2fff),
+    preset(16, 0x1000, 0x2fff),
+    preset(14, 0x0800, 0x2fff),
+    preset(16, 0x0800, 0x2fff),
+];
+
+impl Default for AudioSessionSettings {
+    fn default() -> Self {
+        PRESETS[0]
+    }
+}
+
+impl AudioSessionSettings {
+    /// Returns one of the vanilla presets, if it exists.
+    pub fn preset(id: usize) -> Option<Self> {
+        PRESETS.get(id).copied()
+    }
+
+    // ported from osAiSetFrequency
+    /// The frequency the audio interface actually ends up running at.
+    pub fn ai_frequency(&self) -> u32 {
+        let dac_rate = (VI_CLOCK_NTSC as f32 / self.frequency as f32 + 0.5) as u32;
+        VI_CLOCK_NTSC / dac_rate.max(1)
+    }
+
+    /// Amount of samples rendered every frame, aligned to 16.
+    pub fn samples_per_frame_target(&self) -> u32 {
+        (self.ai_frequency() / FRAMES_PER_SECOND + 15) & !15
+    }
+
+    /// Amount of times the sequence players are processed every frame.
+    pub fn updates_per_frame(&self) -> u32 {
+        self.samples_per_frame_target() / 160 + 1
+    }
+
+    /// Amount of times the sequence players are processed every second.
+    pub fn updates_per_second(&self) -> u32 {
+        self.updates_per_frame() * FRAMES_PER_SECOND
+    }
+
+    /// Tempo accumulator threshold for a tatum to pass.
+    pub fn tempo_internal_to_external(&self) -> u16 {
+        (self.updates_per_frame() as f32 * 2_880_000.0 / TATUMS_PER_BEAT as f32 / 16.713) as u16
+    }
+}
@@ -1,19 +1,16 @@
+use crate::session::AudioSessionSettings;
 use bitflags::bitflags;
+use std::convert::TryInto;
+use std::time::Duration;
 
 const CHANNELS_MAX: u8 = 16;
 const LAYERS_MAX: u8 = 4;
 
-const TATUMS_PER_BEAT: u16 = 48;
+pub(crate) const TATUMS_PER_BEAT: u16 = 48;
 const TEMPO_SCALE: u16 = TATUMS_PER_BEAT;
 
-// AudioSessionSettings stuff i dont really understand
-const FREQUENCY: u32 = 32000;
-const SAMPLES_PER_FRAME_TARGET: u32 = FREQUENCY / 60;
-const UPDATES_PER_FRAME: u32 = SAMPLES_PER_FRAME_TARGET / 160 + 1;
-const TEMPO_INTERNAL_TO_EXTERNAL: u32 =
-    (UPDATES_PER_FRAME as f32 * 2_880_000.0 / TATUMS_PER_BEAT as f32 / 16.713) as u32;
-
 bitflags! {
+    #[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
     pub struct MuteBehavior: u8 {
         const STOP_SCRIPT = 0x80;
         const STOP_NOTES = 0x40;
@@ -21,17 +18,29 @@ bitflags! {
     }
 }
 
+// ported from gDefaultShortNoteVelocityTable
+const DEFAULT_SHORT_NOTE_VELOCITY_TABLE: [u8; 16] = [
+    12, 25, 38, 51, 57, 64, 71, 76, 83, 89, 96, 102, 109, 115, 121, 127,
+];
+// ported from gDefaultShortNoteDurationTable
+const DEFAULT_SHORT_NOTE_DURATION_TABLE: [u8; 16] = [
+    229, 203, 177, 151, 139, 126, 113, 100, 87, 74, 61, 48, 36, 23, 10, 0,
+];
+
 //const NOTE_PRIORITY_DISABLED: u8 = 0;
 //const NOTE_PRIORITY_STOPPING: u8 = 1;
 //const NOTE_PRIORITY_MIN: u8 = 2;
 const NOTE_PRIORITY_DEFAULT: u8 = 3;
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct ScriptState {
     pub pc: u16,
     pub stack: [u16; 4],
     pub rem_loop_iters: [u8; 4],
     pub depth: usize,
+    /// Q, an s8 used to hold temporary script state.
+    pub value: i8,
 }
 
 impl ScriptState {
@@ -41,20 +50,94 @@ impl ScriptState {
             stack: Default::default(),
             rem_loop_iters: Default::default(),
             depth: 0,
+            value: 0,
+        }
+    }
+
+    /// Returns from a function, unless the script is at the top level.
+    fn ret(&mut self) -> bool {
+        if self.depth == 0 {
+            return false;
+        }
+        self.depth -= 1;
+        self.pc = self.stack[self.depth];
+        true
+    }
+
+    // Calls and loops deeper than the stack would overflow it in the game; here they're ignored.
+    fn call(&mut self, addr: u16) {
+        if self.depth < self.stack.len() {
+            self.stack[self.depth] = self.pc;
+            self.depth += 1;
+            self.pc = addr;
+        }
+    }
+
+    fn start_loop(&mut self, count: u8) {
+        if self.depth < self.stack.len() {
+            self.rem_loop_iters[self.depth] = count;
+            self.stack[self.depth] = self.pc;
+            self.depth += 1;
+        }
+    }
+
+    fn end_loop(&mut self) {
+        if self.depth == 0 {
+            return;
+        }
+        let i = self.depth - 1;
+        self.rem_loop_iters[i] = self.rem_loop_iters[i].wrapping_sub(1);
+        if self.rem_loop_iters[i] != 0 {
+            self.pc = self.stack[i];
+        } else {
+            self.depth -= 1;
         }
     }
+
+    /// Reads the command at the program counter and moves past it. Commands cut off by the end of
+    /// the data are read as if it went on with zeros, and `None` is returned past its end.
+    fn read<T>(&mut self, data: &[u8], read: impl FnOnce(&[u8]) -> (T, usize)) -> Option<T> {
+        let data = data
+            .get(self.pc as usize..)
+            .filter(|data| !data.is_empty())?;
+        // the longest commands are 5 bytes long
+        let mut bytes = [0; 8];
+        let len = data.len().min(bytes.len());
+        bytes[..len].copy_from_slice(&data[..len]);
+
+        let (cmd, size) = read(&bytes);
+        self.pc = self.pc.wrapping_add(size as u16);
+        Some(cmd)
+    }
 }
 
-#[derive(Debug)]
+/// A command the interpreter can't run, which stopped the script it was found in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct UnknownCommand {
+    /// Address of the command in the sequence data.
+    pub addr: u16,
+    /// `None` if the address is past the end of the sequence data.
+    pub opcode: Option<u8>,
+}
+
+/// The complete state of a playing sequence, channels and layers included. It's plain data, so a
+/// clone of it is a snapshot that can be assigned back later to restore playback from that point;
+/// with the `serde` feature it can be serialized too.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct SequencePlayer {
     pub finished: bool,
     pub muted: bool,
-    //pub seq_variation: bool,
+    /// Either 0 or -128, depending on the variation bit the sequence was loaded with.
+    pub seq_variation: i8,
     pub state: u8,
     pub note_alloc_policy: u8,
     pub mute_behavior: MuteBehavior,
     //pub seq_id: u8,
-    //pub default_bank: u8,
+    /// Banks usable by this sequence, as listed in the binary format (i.e. backwards). The last
+    /// one is the default bank.
+    pub bank_set: Vec<u8>,
     //pub loading_bank_id: u8,
     //pub loading_bank_num_instruments: u8,
     //pub loading_bank_num_drums: u8,
@@ -63,15 +146,24 @@ pub struct SequencePlayer {
     pub fade_timer: u16,
     pub transposition: i16,
     pub delay: u16,
-    //pub seq_data
+    /// Amount of times `process` has been called since the start of the sequence.
+    pub tick: u64,
+    /// The sequence data, which scripts are allowed to modify.
+    pub seq_data: Vec<u8>,
+    /// The sequence data as it was loaded, restored on reset.
+    original_seq_data: Vec<u8>,
     pub fade_volume: f32,
     pub fade_velocity: f32,
     pub volume: f32,
     pub mute_volume_scale: f32,
     pub channels: [Option<Box<SequenceChannel>>; CHANNELS_MAX as usize],
     pub script_state: ScriptState,
-    // short_velocity_table
-    // short_note_duration_table
+    pub session: AudioSessionSettings,
+    /// Copied out of the sequence data when set, rather than pointing into it.
+    pub short_note_velocity_table: [u8; 16],
+    pub short_note_duration_table: [u8; 16],
+    /// Every command that stopped a script so far, in the order they were found.
+    pub unknown_commands: Vec<UnknownCommand>,
     // note_pool
     // dma things
     // loading_bank
@@ -85,10 +177,16 @@ impl Default for SequencePlayer {
 
 impl SequencePlayer {
     pub fn new() -> Self {
+        Self::with_session(AudioSessionSettings::default())
+    }
+
+    pub fn with_session(session: AudioSessionSettings) -> Self {
         Self {
             finished: false,
             muted: false,
+            seq_variation: 0,
             delay: 0,
+            tick: 0,
             state: 0,
             fade_timer: 0,
             tempo_acc: 0,
@@ -96,8 +194,8 @@ impl SequencePlayer {
             transposition: 0,
             mute_behavior: MuteBehavior::all(),
             note_alloc_policy: 0,
-            //short_note_velocity_table
-            //short_note_duration_table
+            short_note_velocity_table: DEFAULT_SHORT_NOTE_VELOCITY_TABLE,
+            short_note_duration_table: DEFAULT_SHORT_NOTE_DURATION_TABLE,
             fade_volume: 1.0,
             fade_velocity: 0.0,
             volume: 0.0,
@@ -105,42 +203,92 @@ impl SequencePlayer {
 
             script_state: ScriptState::new(0),
             channels: Default::default(),
+            session,
+            seq_data: Vec::new(),
+            original_seq_data: Vec::new(),
+            bank_set: Vec::new(),
+            unknown_commands: Vec::new(),
+        }
+    }
+
+    /// Loads a sequence into the player, and puts it back in its initial state.
+    pub fn load(&mut self, data: Vec<u8>) {
+        self.original_seq_data = data;
+        self.reset();
+    }
+
+    /// Puts the player back in its initial state, keeping its session settings and bank set and
+    /// restoring the sequence data as it was loaded.
+    pub fn reset(&mut self) {
+        let original_seq_data = std::mem::take(&mut self.original_seq_data);
+        let bank_set = std::mem::take(&mut self.bank_set);
+        *self = Self::with_session(self.session);
+        self.seq_data = original_seq_data.clone();
+        self.original_seq_data = original_seq_data;
+        self.bank_set = bank_set;
+    }
+
+    pub fn default_bank(&self) -> u8 {
+        self.bank_set.last().copied().unwrap_or(0)
+    }
+
+    /// Fast-forwards the sequence up to the given tick, running the scripts without
+    /// rendering anything. Seeking backwards restarts the sequence from the beginning.
+    ///
+    /// The resulting state is exactly the one regular playback would have reached,
+    /// including which notes are sounding at that point.
+    pub fn seek(&mut self, tick: u64) {
+        if tick < self.tick {
+            self.reset();
+        }
+        while self.tick < tick && !self.finished {
+            self.process();
         }
     }
 
+    /// Same as `seek`, but with a position in time.
+    pub fn seek_to_time(&mut self, time: Duration) {
+        let tick = time.as_secs_f64() * self.session.updates_per_second() as f64;
+        self.seek(tick as u64);
+    }
+
+    /// The current playback position in time.
+    pub fn time(&self) -> Duration {
+        Duration::from_secs_f64(self.tick as f64 / self.session.updates_per_second() as f64)
+    }
+
     // ported from sequence_player_process_sequence
-    pub fn process(&mut self, data: &[u8]) {
+    pub fn process(&mut self) {
+        self.tick += 1;
+
         if self.muted && self.mute_behavior.contains(MuteBehavior::STOP_SCRIPT) {
             return;
         }
 
         // Check if we surpass the number of ticks needed for a tatum, else stop.
+        let tempo_internal_to_external = self.session.tempo_internal_to_external();
         self.tempo_acc += self.tempo;
-        if self.tempo_acc < TEMPO_INTERNAL_TO_EXTERNAL as u16 {
+        if self.tempo_acc < tempo_internal_to_external {
             return;
         }
-        self.tempo_acc -= TEMPO_INTERNAL_TO_EXTERNAL as u16;
+        self.tempo_acc -= tempo_internal_to_external;
 
         if self.delay > 1 {
             self.delay -= 1;
         } else {
-            let value: Option<i8> = None;
-
             loop {
                 use crate::sequence::SequenceCmd::{self, *};
-                let (cmd, size) = SequenceCmd::read(&data[self.script_state.pc as usize..]);
-                self.script_state.pc += size as u16;
-                println!("{:x?}", cmd);
+                let addr = self.script_state.pc;
+                let cmd = self.script_state.read(&self.seq_data, SequenceCmd::read);
 
                 let state = &mut self.script_state;
-                match cmd {
+                // running past the end of the data is reported like an unknown command
+                match cmd.unwrap_or(Unknown(0)) {
                     End => {
-                        if state.depth == 0 {
+                        if !state.ret() {
                             self.finished = true;
                             break;
                         }
-                        state.depth -= 1;
-                        state.pc = state.stack[state.depth];
                     }
 
                     Delay(delay) => {
@@ -153,41 +301,31 @@ impl SequencePlayer {
                     }
 
                     Call(addr) => {
-                        state.stack[state.depth] = state.pc;
-                        state.depth += 1;
-                        state.pc = addr;
+                        state.call(addr);
                     }
 
                     Loop(count) => {
-                        state.rem_loop_iters[state.depth] = count;
-                        state.depth += 1;
-                        state.stack[state.depth - 1] = state.pc;
+                        state.start_loop(count);
                     }
                     LoopEnd => {
-                        state.rem_loop_iters[state.depth - 1] =
-                            state.rem_loop_iters[state.depth - 1].wrapping_sub(1);
-                        if state.rem_loop_iters[state.depth - 1] != 0 {
-                            state.pc = state.stack[state.depth - 1];
-                        } else {
-                            state.depth -= 1;
-                        }
+                        state.end_loop();
                     }
 
                     Jump(addr) => {
                         state.pc = addr;
                     }
                     Beqz(addr) => {
-                        if value.unwrap() == 0 {
+                        if state.value == 0 {
                             state.pc = addr;
                         }
                     }
                     Bltz(addr) => {
-                        if value.unwrap() < 0 {
+                        if state.value < 0 {
                             state.pc = addr;
                         }
                     }
                     Bgez(addr) => {
-                        if value.unwrap() >= 0 {
+                        if state.value >= 0 {
                             state.pc = addr;
                         }
                     }
@@ -203,14 +341,33 @@ impl SequencePlayer {
                         self.transposition = transposition as i16;
                     }
                     TransposeRel(transposition) => {
-                        self.transposition += transposition as i16;
+                        self.transposition = self.transposition.wrapping_add(transposition as i16);
                     }
 
-                    // ...
+                    SetMuteScale(scale) => {
+                        self.mute_volume_scale = scale as f32 / 127.0;
+                    }
+                    Mute => {
+                        self.muted = true;
+                    }
                     SetMuteBhv(bhv) => {
                         self.mute_behavior = MuteBehavior::from_bits_truncate(bhv);
                     }
 
+                    SetShortNoteVelocityTable(addr) => {
+                        if let Some(table) = self.seq_table(addr) {
+                            self.short_note_velocity_table = table;
+                        }
+                    }
+                    SetShortNoteDurationTable(addr) => {
+                        if let Some(table) = self.seq_table(addr) {
+                            self.short_note_duration_table = table;
+                        }
+                    }
+                    SetNoteAllocationPolicy(policy) => {
+                        self.note_alloc_policy = policy;
+                    }
+
                     InitChannels(mask) => {
                         self.init_channels(mask);
                     }
@@ -220,16 +377,51 @@ impl SequencePlayer {
                     DisableChannels(mask) => {
                         self.disable_channels(mask);
                     }
+                    TestChDisabled(i) => {
+                        if let Some(channel) = &self.channels[i as usize] {
+                            state.value = channel.finished as i8;
+                        }
+                    }
 
                     SetVol(vol) => {
                         self.volume = vol as f32 / 127.0;
                     }
+                    ChangeVol(delta) => {
+                        self.volume += delta as f32 / 127.0;
+                    }
                     SetTempo(tempo) => {
                         self.tempo = tempo as u16 * TEMPO_SCALE;
                     }
+                    AddTempo(delta) => {
+                        let tempo = self.tempo as i32 + delta as i32 * TEMPO_SCALE as i32;
+                        self.tempo = tempo.clamp(1, tempo_internal_to_external as i32) as u16;
+                    }
+
+                    SetVal(val) => {
+                        state.value = val as i8;
+                    }
+                    BitAnd(val) => {
+                        state.value &= val as i8;
+                    }
+                    Subtract(val) => {
+                        state.value = state.value.wrapping_sub(val as i8);
+                    }
+
+                    GetVariation => {
+                        state.value = self.seq_variation;
+                    }
+                    SetVariation => {
+                        self.seq_variation = state.value;
+                    }
+                    SubVariation => {
+                        state.value = state.value.wrapping_sub(self.seq_variation);
+                    }
 
-                    // ...
-                    _ => todo!("player cmd {:x?}", cmd),
+                    Unknown(_) => {
+                        self.report_unknown(addr);
+                        self.finished = true;
+                        break;
+                    }
                 }
             }
         }
@@ -238,18 +430,43 @@ impl SequencePlayer {
             if self.channels[i].is_some() {
                 // workaround so we can borrow self and the channel at the same time
                 let mut channel = std::mem::take(&mut self.channels[i]);
-                channel.as_mut().unwrap().process(&self, data);
+                channel.as_mut().unwrap().process(self);
                 self.channels[i] = channel;
             }
         }
     }
 
+    /// The 16 bytes at the given address of the sequence data, if they're all in it.
+    fn seq_table(&self, addr: u16) -> Option<[u8; 16]> {
+        let data = self.seq_data.get(addr as usize..)?.get(..16)?;
+        data.try_into().ok()
+    }
+
+    /// The big-endian u16 at the given address of the sequence data.
+    fn seq_u16(&self, addr: u16) -> Option<u16> {
+        let data = self.seq_data.get(addr as usize..)?.get(..2)?;
+        Some(u16::from_be_bytes([data[0], data[1]]))
+    }
+
+    fn report_unknown(&mut self, addr: u16) {
+        let cmd = UnknownCommand {
+            addr,
+            opcode: self.seq_data.get(addr as usize).copied(),
+        };
+        if !self.unknown_commands.contains(&cmd) {
+            self.unknown_commands.push(cmd);
+        }
+    }
+
     // ported from sequence_player_init_channels
     fn init_channels(&mut self, mask: u16) {
+        let default_bank = self.default_bank();
         let mut mask = mask;
         for channel_slot in &mut self.channels {
             if mask & 1 != 0 {
-                *channel_slot = Some(Box::new(SequenceChannel::new()));
+                let mut channel = SequenceChannel::new();
+                channel.bank_id = default_bank;
+                *channel_slot = Some(Box::new(channel));
             }
             mask >>= 1;
         }
@@ -279,7 +496,21 @@ impl SequencePlayer {
     }
 }
 
-#[derive(Debug)]
+/// What a channel plays its notes with, as set by `SetInstr`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum ChannelInstrument {
+    None,
+    /// Notes select a drum from the bank by their semitone.
+    Drums,
+    /// One of the raw waves: 0x80 to 0x83 for sawtooth, triangle, sine and square.
+    Wave(u8),
+    /// An instrument within the channel's bank.
+    Instrument(u8),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct SequenceChannel {
     pub enabled: bool,
     pub finished: bool,
@@ -292,7 +523,7 @@ pub struct SequenceChannel {
     //pub mute_behavior: MuteBehavior,
     pub reverb: u8,
     pub note_priority: u8,
-    //pub bank_id: u8,
+    pub bank_id: u8,
     //pub updates_per_frame_unused,
     pub vibrato_rate_start: u16,
     pub vibrato_extent_start: u16,
@@ -302,14 +533,15 @@ pub struct SequenceChannel {
     pub vibrato_extent_change_delay: u16,
     pub vibrato_delay: u16,
     pub delay: u16,
-    //pub instr_or_wave: i16,
+    pub instrument: ChannelInstrument,
     pub transposition: i16,
     pub volume_scale: f32,
     pub volume: f32,
     pub pan: f32,
     pub pan_channel_weight: f32,
     pub freq_scale: f32,
-    //pub dyn_table_addr: u16,
+    /// Address of the table of script addresses used by the dynamic commands.
+    pub dyn_table_addr: Option<u16>,
     // note_unused
     // layer_unused
     // instrument
@@ -327,6 +559,8 @@ impl SequenceChannel {
             finished: false,
             stop_script: false,
             has_instrument: false,
+            bank_id: 0,
+            instrument: ChannelInstrument::None,
             stereo_headset_effects: false,
             transposition: 0,
             large_notes: false,
@@ -334,6 +568,7 @@ impl SequenceChannel {
             volume: 1.0,
             volume_scale: 1.0,
             freq_scale: 1.0,
+            dyn_table_addr: None,
             pan: 0.5,
             pan_channel_weight: 1.0,
             reverb: 0,
@@ -357,14 +592,14 @@ impl SequenceChannel {
     }
 
     // ported from sequence_channel_process_script
-    pub fn process(&mut self, player: &SequencePlayer, data: &[u8]) {
+    pub fn process(&mut self, player: &mut SequencePlayer) {
         if !self.enabled {
             return;
         }
 
         if self.stop_script {
             for j in 0..self.layers.len() {
-                self.process_layer(j, data);
+                self.process_layer(j, player);
             }
             return;
         }
@@ -380,20 +615,18 @@ impl SequenceChannel {
         if self.delay == 0 {
             loop {
                 use crate::channel::ChannelCmd::{self, *};
-                let (cmd, size) = ChannelCmd::read(&data[self.script_state.pc as usize..]);
-                self.script_state.pc += size as u16;
-                println!("channel: {:x?}", cmd);
+                let addr = self.script_state.pc;
+                let cmd = self.script_state.read(&player.seq_data, ChannelCmd::read);
 
                 let state = &mut self.script_state;
-                match cmd {
+                // running past the end of the data is reported like an unknown command
+                match cmd.unwrap_or(Unknown(0)) {
                     End => {
-                        if state.depth == 0 {
+                        if !state.ret() {
                             self.enabled = false;
                             self.finished = true;
                             break;
                         }
-                        state.depth -= 1;
-                        state.pc = state.stack[state.depth];
                     }
 
                     Delay1 => {
@@ -403,62 +636,309 @@ impl SequenceChannel {
                         self.delay = delay;
                         break;
                     }
+                    Hang => {
+                        self.stop_script = true;
+                        break;
+                    }
+
+                    Call(addr) => {
+                        state.call(addr);
+                    }
+                    Jump(addr) => {
+                        state.pc = addr;
+                    }
+                    Beqz(addr) => {
+                        if state.value == 0 {
+                            state.pc = addr;
+                        }
+                    }
+                    Bltz(addr) => {
+                        if state.value < 0 {
+                            state.pc = addr;
+                        }
+                    }
+                    Bgez(addr) => {
+                        if state.value >= 0 {
+                            state.pc = addr;
+                        }
+                    }
+
+                    Loop(count) => {
+                        state.start_loop(count);
+                    }
+                    LoopEnd => {
+                        state.end_loop();
+                    }
+                    Break => {
+                        state.depth = state.depth.saturating_sub(1);
+                    }
+
+                    ReserveNotes(_amt) => {
+                        // TODO
+                    }
+                    UnReserveNotes => {
+                        // TODO
+                    }
+
+                    // the dynamic commands do nothing for Q = -1, and other negative values would
+                    // read before the table
+                    DynCall => {
+                        if let Some(addr) = self.dyn_table_entry(player) {
+                            self.script_state.call(addr);
+                        }
+                    }
+                    DynSetDynTable => {
+                        if let Some(addr) = self.dyn_table_entry(player) {
+                            self.dyn_table_addr = Some(addr);
+                        }
+                    }
+                    DynSetLayer(j) => {
+                        if let Some(addr) = self.dyn_table_entry(player) {
+                            self.set_layer(j, addr);
+                        }
+                    }
+                    SetDynTable(addr) => {
+                        self.dyn_table_addr = Some(addr);
+                    }
+
+                    SetVibratoDelay(delay) => {
+                        self.vibrato_delay = delay as u16 * 16;
+                    }
+                    SetVibratoExtentLinear(start, target, delay) => {
+                        self.vibrato_extent_start = start as u16 * 8;
+                        self.vibrato_extent_target = target as u16 * 8;
+                        self.vibrato_extent_change_delay = delay as u16 * 16;
+                    }
+                    SetVibratoRateLinear(start, target, delay) => {
+                        self.vibrato_rate_start = start as u16 * 32;
+                        self.vibrato_rate_target = target as u16 * 32;
+                        self.vibrato_rate_change_delay = delay as u16 * 16;
+                    }
+                    SetVibratoExtent(extent) => {
+                        self.vibrato_extent_start = 0;
+                        self.vibrato_extent_target = extent as u16 * 8;
+                        self.vibrato_extent_change_delay = 0;
+                    }
+                    SetVibratoRate(rate) => {
+                        self.vibrato_rate_start = rate as u16 * 32;
+                        self.vibrato_rate_target = rate as u16 * 32;
+                        self.vibrato_rate_change_delay = 0;
+                    }
+
+                    SetEnvelope(_addr) => {
+                        // TODO: adsr
+                    }
+                    SetDecayRelease(_rate) => {
+                        // TODO: adsr
+                    }
+                    SetSustain(_sustain) => {
+                        // TODO: adsr
+                    }
 
-                    // ...
                     Transpose(trans) => {
                         self.transposition = trans as i16;
                     }
+                    FreqScale(scale) => {
+                        self.freq_scale = scale as f32 / 32768.0;
+                    }
+                    // gPitchBendFrequencyScale goes from an octave down to an octave up
+                    PitchBend(bend) => {
+                        self.freq_scale = 2f32.powf(bend as f32 / 127.0);
+                    }
 
                     LargeNotesOn => {
                         self.large_notes = true;
                     }
+                    LargeNotesOff => {
+                        self.large_notes = false;
+                    }
                     SetLayer(j, addr) => {
-                        // from seq_channel_set_layer
-                        // NOTE: does not check for the global layer limit
-                        if self.layers[j as usize].is_some() {
-                            // TODO: seq_channel_layer_note_decay
+                        self.set_layer(j, addr);
+                    }
+                    FreeLayer(j) => {
+                        if let Some(layer) = self.layers.get_mut(j as usize) {
+                            *layer = None;
+                        }
+                    }
+                    TestLayerFinished(j) => {
+                        if let Some(Some(layer)) = self.layers.get(j as usize) {
+                            state.value = layer.finished as i8;
                         }
-
-                        let layer = SequenceLayer::new(addr, &self);
-                        self.layers[j as usize] = Some(Box::new(layer));
                     }
+
                     SetVol(vol) => {
                         self.volume = vol as f32 / 127.0;
                     }
+                    SetVolScale(scale) => {
+                        self.volume_scale = scale as f32 / 128.0;
+                    }
                     SetNotePriority(np) => {
                         self.note_priority = np;
                     }
                     SetPan(pan) => {
                         self.pan = pan as f32 / 128.0;
                     }
+                    SetPanChanWeight(weight) => {
+                        self.pan_channel_weight = weight as f32 / 128.0;
+                    }
                     SetReverb(reverb) => {
                         self.reverb = reverb;
                     }
-                    SetInstr(_) => {
-                        // big fat TODO
+                    StereoHeadsetEffects(enabled) => {
+                        self.stereo_headset_effects = enabled != 0;
+                    }
+                    SetUpdatesPerFrame(_) => {
+                        // does nothing in the game either
+                    }
+                    SetNoteAllocationPolicy(_) | SetMuteBhv(_) => {
+                        // TODO
+                    }
+                    SetInstr(id) => {
+                        self.set_instrument(id);
+                    }
+                    SetBank(i) => {
+                        // banks are listed backwards
+                        let bank_set = &player.bank_set;
+                        if let Some(&bank_id) =
+                            bank_set.get(bank_set.len().wrapping_sub(i as usize + 1))
+                        {
+                            self.bank_id = bank_id;
+                        }
+                    }
+
+                    SetVal(val) => {
+                        state.value = val as i8;
+                    }
+                    BitAnd(val) => {
+                        state.value &= val as i8;
+                    }
+                    Subtract(val) => {
+                        state.value = state.value.wrapping_sub(val as i8);
+                    }
+                    // addresses outside of the sequence data are ignored
+                    ReadSeq(addr) => {
+                        let addr = addr.wrapping_add(state.value as u16);
+                        if let Some(&byte) = player.seq_data.get(addr as usize) {
+                            state.value = byte as i8;
+                        }
+                    }
+                    WriteSeq(val, addr) => {
+                        if let Some(byte) = player.seq_data.get_mut(addr as usize) {
+                            *byte = (state.value as u8).wrapping_add(val);
+                        }
+                    }
+
+                    // the IO arrays only have 8 slots, so higher ones are ignored
+                    IoReadVal(n) => {
+                        let n = n as usize;
+                        if let Some(&value) = self.sound_script_io.get(n) {
+                            state.value = value;
+                            if n < 4 {
+                                self.sound_script_io[n] = -1;
+                            }
+                        }
+                    }
+                    IoWriteVal(n) => {
+                        if let Some(io) = self.sound_script_io.get_mut(n as usize) {
+                            *io = state.value;
+                        }
+                    }
+                    IoReadValSub(n) => {
+                        if let Some(&value) = self.sound_script_io.get(n as usize) {
+                            state.value = state.value.wrapping_sub(value);
+                        }
+                    }
+                    // the channel being processed isn't in the player, so it can't be addressed
+                    // this way
+                    IoReadVal2(i, n) => {
+                        let channel = player.channels[i as usize].as_ref();
+                        if let Some(&value) =
+                            channel.and_then(|channel| channel.sound_script_io.get(n as usize))
+                        {
+                            state.value = value;
+                        }
+                    }
+                    IoWriteVal2(i, n) => {
+                        let channel = player.channels[i as usize].as_mut();
+                        if let Some(io) =
+                            channel.and_then(|channel| channel.sound_script_io.get_mut(n as usize))
+                        {
+                            *io = state.value;
+                        }
                     }
 
-                    _ => todo!("channel cmd {:x?}", cmd),
+                    DisableChannel(i) => {
+                        if let Some(channel) = player.channels[i as usize].as_mut() {
+                            channel.disable();
+                        }
+                    }
+                    StartChannel(i, addr) => {
+                        player.start_channel(i, addr);
+                    }
+
+                    Unknown(_) => {
+                        player.report_unknown(addr);
+                        self.enabled = false;
+                        self.finished = true;
+                        break;
+                    }
                 }
             }
         }
 
         for j in 0..self.layers.len() {
-            self.process_layer(j, data);
+            self.process_layer(j, player);
         }
     }
 
-    fn process_layer(&mut self, j: usize, data: &[u8]) {
+    // ported from seq_channel_set_layer
+    // NOTE: does not check for the global layer limit
+    fn set_layer(&mut self, j: u8, addr: u16) {
+        if (j as usize) < self.layers.len() {
+            let layer = SequenceLayer::new(addr, self);
+            self.layers[j as usize] = Some(Box::new(layer));
+        }
+    }
+
+    // ported from sequence_channel_disable
+    fn disable(&mut self) {
+        self.enabled = false;
+        self.finished = true;
+        for layer_slot in &mut self.layers {
+            *layer_slot = None;
+        }
+    }
+
+    /// The script address at index Q of the dynamic table.
+    fn dyn_table_entry(&self, player: &SequencePlayer) -> Option<u16> {
+        let index: u16 = self.script_state.value.try_into().ok()?;
+        player.seq_u16(self.dyn_table_addr?.wrapping_add(index * 2))
+    }
+
+    // ported from set_instrument
+    /// Whether the instrument actually exists is only known once it's resolved against the
+    /// channel's bank.
+    fn set_instrument(&mut self, id: u8) {
+        self.instrument = match id {
+            0x80..=0xff => ChannelInstrument::Wave(id),
+            0x7f => ChannelInstrument::Drums,
+            _ => ChannelInstrument::Instrument(id),
+        };
+        self.has_instrument = true;
+    }
+
+    fn process_layer(&mut self, j: usize, player: &mut SequencePlayer) {
         if self.layers[j].is_some() {
             // same as above
             let mut layer = std::mem::take(&mut self.layers[j]);
-            layer.as_mut().unwrap().process(&self, data);
+            layer.as_mut().unwrap().process(self, player);
             self.layers[j] = layer;
         }
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct SequenceLayer {
     pub enabled: bool,
     pub finished: bool,
@@ -477,18 +957,21 @@ pub struct SequenceLayer {
     //note_velocity: f32,
     //note_pan: f32,
     //note_freq_scale: f32,
-    //short_note_default_play_percentage: i16,
+    pub short_note_default_play_percentage: Option<i16>,
     pub play_percentage: Option<i16>,
     pub delay: i16,
     pub duration: i16,
     pub delay_unused: i16,
     //note
-    //instrument
+    /// Overrides the channel's instrument, as set by `SetInstr`.
+    pub instrument: Option<ChannelInstrument>,
     //sound
     //seq_channel
     pub script_state: ScriptState,
     //listItem
     pub pitch: Option<u8>,
+    /// Amount of notes played so far, telling apart consecutive notes of the same pitch.
+    pub note_count: u32,
 }
 
 impl SequenceLayer {
@@ -509,17 +992,19 @@ impl SequenceLayer {
             duration: 0,
             delay_unused: 0,
             //note: None
-            //instrument: NOne
+            instrument: None,
             velocity_square: 0.0,
-            pan: 0.0,
+            pan: 0.5,
 
+            short_note_default_play_percentage: None,
             play_percentage: None,
             pitch: None,
+            note_count: 0,
         }
     }
 
     // from seq_channel_layer_process_script
-    pub fn process(&mut self, channel: &SequenceChannel, data: &[u8]) {
+    pub fn process(&mut self, channel: &SequenceChannel, player: &mut SequencePlayer) {
         if !self.enabled {
             return;
         }
@@ -527,53 +1012,93 @@ impl SequenceLayer {
         if self.delay > 1 {
             self.delay -= 1;
             if !self.stop_something && self.delay <= self.duration {
-                // seq_channel_layer_note_decay
+                self.note_decay();
                 self.stop_something = true;
-
-                self.pitch = None;
             }
             return;
         }
 
         if !self.continuous_notes {
-            // seq_channel_layer_note_decay
+            self.note_decay();
         }
 
         // TODO: check portamento
         loop {
             use crate::layer::LayerCmd::{self, *};
-            let (cmd, size) =
-                LayerCmd::read(&data[self.script_state.pc as usize..], channel.large_notes);
-            self.script_state.pc += size as u16;
-            println!("layer: {:x?}", cmd);
+            let addr = self.script_state.pc;
+            let cmd = self.script_state.read(&player.seq_data, |data| {
+                LayerCmd::read(data, channel.large_notes)
+            });
 
             let state = &mut self.script_state;
-            match cmd {
+            // running past the end of the data is reported like an unknown command
+            match cmd.unwrap_or(Unknown(0)) {
                 End => {
-                    if state.depth == 0 {
+                    if !state.ret() {
                         self.enabled = false;
+                        self.finished = true;
                         return;
                     }
-                    state.depth -= 1;
-                    state.pc = state.stack[state.depth];
                 }
 
-                // ...
                 Delay(delay) => {
                     self.delay = delay as i16;
                     self.stop_something = true;
+                    self.note_decay();
                     break;
                 }
 
                 Call(addr) => {
-                    state.stack[state.depth] = state.pc;
-                    state.depth += 1;
+                    state.call(addr);
+                }
+                Jump(addr) => {
                     state.pc = addr;
                 }
+                Loop(count) => {
+                    state.start_loop(count);
+                }
+                LoopEnd => {
+                    state.end_loop();
+                }
 
                 Transpose(trans) => {
                     self.transposition = trans as i16;
                 }
+                SetPan(pan) => {
+                    self.pan = pan as f32 / 128.0;
+                }
+                SetInstr(id) => {
+                    // drums and waves can only be set on the channel
+                    if id < 0x7f {
+                        self.instrument = Some(ChannelInstrument::Instrument(id));
+                    }
+                }
+                SomethingOn => {
+                    self.continuous_notes = true;
+                }
+                SomethingOff => {
+                    self.continuous_notes = false;
+                }
+                DisablePortamento | Portamento(..) => {
+                    // TODO: portamento
+                }
+
+                SetShortNoteVelocity(velocity) => {
+                    self.velocity_square = (velocity as f32).powi(2);
+                }
+                SetShortNoteVelocityFromTable(i) => {
+                    let velocity = player.short_note_velocity_table[i as usize];
+                    self.velocity_square = (velocity as f32).powi(2);
+                }
+                SetShortNoteDuration(duration) => {
+                    self.note_duration = duration;
+                }
+                SetShortNoteDurationFromTable(i) => {
+                    self.note_duration = player.short_note_duration_table[i as usize];
+                }
+                SetShortNoteDefaultPlayPercentage(percentage) => {
+                    self.short_note_default_play_percentage = Some(percentage as i16);
+                }
 
                 Note0 {
                     percentage,
@@ -581,16 +1106,10 @@ impl SequenceLayer {
                     velocity,
                     pitch,
                 } => {
-                    // TODO
-                    self.stop_something = false;
                     self.note_duration = duration;
                     self.play_percentage = Some(percentage as i16);
                     self.velocity_square = (velocity as f32).powi(2);
-                    self.delay = percentage as i16;
-                    self.duration = (self.note_duration as u32 * percentage as u32 / 256) as i16;
-
-                    // TODO: etc
-                    self.pitch = Some(pitch);
+                    self.play_note(pitch, percentage as i16);
                     break;
                 }
                 Note1 {
@@ -601,10 +1120,7 @@ impl SequenceLayer {
                     self.note_duration = 0;
                     self.play_percentage = Some(percentage as i16);
                     self.velocity_square = (velocity as f32).powi(2);
-                    self.delay = percentage as i16;
-                    self.duration = (self.note_duration as u32 * percentage as u32 / 256) as i16;
-
-                    self.pitch = Some(pitch);
+                    self.play_note(pitch, percentage as i16);
                     break;
                 }
                 Note2 {
@@ -612,20 +1128,279 @@ impl SequenceLayer {
                     velocity,
                     pitch,
                 } => {
-                    self.stop_something = false;
                     self.note_duration = duration;
                     self.velocity_square = (velocity as f32).powi(2);
-                    let percentage = self.play_percentage.unwrap();
-                    self.delay = percentage as i16;
-                    self.duration = (self.note_duration as u32 * percentage as u32 / 256) as i16;
-
-                    self.pitch = Some(pitch);
+                    // unset play percentages are whatever was left in memory in the game
+                    self.play_note(pitch, self.play_percentage.unwrap_or(0));
+                    break;
+                }
+                SmallNote0 { pitch, percentage } => {
+                    self.play_percentage = Some(percentage as i16);
+                    self.play_note(pitch, percentage as i16);
+                    break;
+                }
+                SmallNote1 { pitch } => {
+                    let percentage = self.short_note_default_play_percentage.unwrap_or(0);
+                    self.play_note(pitch, percentage);
+                    break;
+                }
+                SmallNote2 { pitch } => {
+                    self.play_note(pitch, self.play_percentage.unwrap_or(0));
                     break;
                 }
 
-                // ...
-                _ => todo!("layer cmd {:x?}", cmd),
+                Unknown(_) => {
+                    player.report_unknown(addr);
+                    self.enabled = false;
+                    self.finished = true;
+                    return;
+                }
             }
         }
     }
+
+    /// Starts a note lasting for the given play percentage, with the duration and velocity set
+    /// beforehand.
+    fn play_note(&mut self, pitch: u8, percentage: i16) {
+        self.stop_something = false;
+        self.delay = percentage;
+        self.duration = (self.note_duration as u32 * percentage as u32 / 256) as i16;
+
+        self.pitch = Some(pitch);
+        self.note_count = self.note_count.wrapping_add(1);
+    }
+
+    // ported from seq_channel_layer_note_decay
+    /// Releases the note being played, if any.
+    fn note_decay(&mut self) {
+        self.pitch = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Runs a channel script on channel 0 for a tick, and returns the player.
+    fn run_channel(channel: &[u8]) -> SequencePlayer {
+        let mut data = vec![
+            0xd7, 0x00, 0x01, // init channel 0
+            0x90, 0x00, 0x09, // start channel 0
+            0xfd, 0x8f, 0xff, // delay
+        ];
+        data.extend_from_slice(channel);
+
+        let mut player = SequencePlayer::new();
+        player.load(data);
+        // a tatum every tick
+        player.tempo = player.session.tempo_internal_to_external();
+        player.process();
+        player
+    }
+
+    #[test]
+    fn seq_data_access_out_of_range() {
+        let player = run_channel(&[
+            0xc7, 0x05, 0xff, 0xf0, // write past the end
+            0xcb, 0xff, 0xf0, // read past the end, leaving the value at 0
+            0xc7, 0x01, 0x00, 0x15, // write value + 1 to the last byte
+            0xff, // end
+            0x00,
+        ]);
+        assert_eq!(player.seq_data.len(), 0x16);
+        assert_eq!(player.seq_data[0x15], 1);
+    }
+
+    /// A sequence that never ends, whose channel keeps bumping the velocity of its layer's note.
+    fn looping_sequence() -> Vec<u8> {
+        vec![
+            0xd7, 0x00, 0x01, // init channel 0
+            0x90, 0x00, 0x09, // start channel 0
+            0xfd, 0x8f, 0xff, // delay
+            // channel 0
+            0xc4, // large notes
+            0x90, 0x00, 0x1b, // set layer 0
+            0xcc, 0x00, // loop: value = 0
+            0xcb, 0x00, 0x1d, // read the note velocity
+            0xc7, 0x01, 0x00, 0x1d, // write it back, plus one
+            0xfd, 0x20, // delay
+            0xfb, 0x00, 0x0d, // jump to the loop
+            // layer 0
+            0x27, 0x10, 0x40, 0x08, // large note0
+            0xfb, 0x00, 0x1b, // jump to the note
+        ]
+    }
+
+    fn looping_player() -> SequencePlayer {
+        let mut player = SequencePlayer::new();
+        player.load(looping_sequence());
+        player
+    }
+
+    #[test]
+    fn seek_matches_playback() {
+        let mut seeked = looping_player();
+        seeked.seek(500);
+
+        let mut played = looping_player();
+        for _ in 0..500 {
+            played.process();
+        }
+        assert_ne!(played.seq_data, looping_sequence());
+        assert_eq!(seeked, played);
+    }
+
+    #[test]
+    fn seek_backwards() {
+        let mut seeked = looping_player();
+        seeked.seek(500);
+        seeked.seek(100);
+
+        let mut fresh = looping_player();
+        fresh.seek(100);
+        assert_eq!(seeked, fresh);
+    }
+
+    #[test]
+    fn seek_to_time() {
+        let mut player = looping_player();
+        let time = Duration::from_millis(2500);
+        player.seek_to_time(time);
+
+        let tick = Duration::from_secs_f64(1.0 / player.session.updates_per_second() as f64);
+        assert!(player.time() <= time);
+        assert!(time - player.time() < tick);
+    }
+
+    #[test]
+    fn clone_snapshot() {
+        let mut player = looping_player();
+        player.seek(300);
+        let mut snapshot = player.clone();
+        assert_eq!(snapshot, player);
+
+        player.seek(500);
+        snapshot.seek(500);
+        assert_eq!(snapshot, player);
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn serde_snapshot() {
+        let mut player = looping_player();
+        player.seek(300);
+        let bytes = bincode::serialize(&player).unwrap();
+        let mut snapshot: SequencePlayer = bincode::deserialize(&bytes).unwrap();
+        assert_eq!(snapshot, player);
+
+        player.seek(500);
+        snapshot.seek(500);
+        assert_eq!(snapshot, player);
+    }
+
+    #[test]
+    fn reset_restores_seq_data() {
+        let mut player = looping_player();
+        player.bank_set = vec![3];
+        player.seek(500);
+        assert_ne!(player.seq_data, looping_sequence());
+
+        player.reset();
+        assert_eq!(player.seq_data, looping_sequence());
+        assert_eq!(player.bank_set, vec![3]);
+        player.bank_set.clear();
+        assert_eq!(player, looping_player());
+    }
+
+    /// Runs a layer script on layer 0 of channel 0 for a tick, and returns the player.
+    fn run_layer(large_notes: bool, layer: &[u8]) -> SequencePlayer {
+        let mut channel = vec![
+            if large_notes { 0xc4 } else { 0xc3 },
+            0x90,
+            0x00,
+            0x10, // set layer 0
+            0xfd,
+            0x8f,
+            0xff, // delay
+        ];
+        channel.extend_from_slice(layer);
+        run_channel(&channel)
+    }
+
+    #[test]
+    fn layer_commands() {
+        let player = run_layer(
+            false,
+            &[
+                0xca, 0x00, // pan left
+                0xc6, 0x05, // instrument 5
+                0xc1, 0x40, // short note velocity
+                0xc9, 0x80, // short note duration
+                0x27, 0x10, // small note0
+                0xff,
+            ],
+        );
+        let channel = player.channels[0].as_ref().unwrap();
+        let layer = channel.layers[0].as_ref().unwrap();
+        assert_eq!(layer.pan, 0.0);
+        assert_eq!(layer.instrument, Some(ChannelInstrument::Instrument(5)));
+        assert_eq!(layer.velocity_square, 64.0 * 64.0);
+        assert_eq!(layer.pitch, Some(0x27));
+        assert_eq!((layer.delay, layer.duration), (0x10, 0x08));
+        assert!(player.unknown_commands.is_empty());
+    }
+
+    #[test]
+    fn unset_play_percentage() {
+        // note2 without any earlier play percentage
+        let player = run_layer(true, &[0x80 + 0x27, 0x7f, 0x00, 0xff]);
+        let layer = player.channels[0].as_ref().unwrap().layers[0]
+            .as_ref()
+            .unwrap();
+        assert_eq!(layer.pitch, Some(0x27));
+        assert_eq!(layer.delay, 0);
+    }
+
+    #[test]
+    fn unknown_commands_stop_their_script() {
+        // a channel command, then a layer command that don't exist
+        let player = run_channel(&[
+            0x90, 0x00, 0x0d, // set layer 0
+            0xcd, // unknown
+            0xf0, // layer: unknown
+        ]);
+        assert!(!player.finished);
+        let channel = player.channels[0].as_ref().unwrap();
+        assert!(!channel.enabled && channel.finished);
+        assert!(channel.layers[0].as_ref().unwrap().finished);
+        assert_eq!(
+            player.unknown_commands,
+            [
+                UnknownCommand {
+                    addr: 0x0c,
+                    opcode: Some(0xcd)
+                },
+                UnknownCommand {
+                    addr: 0x0d,
+                    opcode: Some(0xf0)
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn running_past_the_end() {
+        // both the channel and the layer it starts run past the end, which is only reported once
+        let player = run_channel(&[0x90, 0x00, 0x0c]);
+        assert_eq!(
+            player.unknown_commands,
+            [UnknownCommand {
+                addr: 0x0c,
+                opcode: None
+            }]
+        );
+        let channel = player.channels[0].as_ref().unwrap();
+        assert!(channel.finished);
+        assert!(channel.layers[0].as_ref().unwrap().finished);
+    }
 }
@@ -0,0 +1,660 @@
+//! Renders a sequence with the instruments of its banks, loosely ported from the game's note
+//! processing and synthesis (`playback.c`, `effects.c` and `synthesis.c`).
+//!
+//! The synthesizer drives the sequence player itself, processing it at the exact output frame
+//! each tick falls on. Samples are resampled with linear interpolation rather than the game's
+//! filters, and raw waves, vibrato, portamento and reverb aren't supported.
+
+use crate::bank::{Bank, Ctl, Envelope, EnvelopeCmd, SampleTable, Sound};
+use crate::state::{
+    ChannelInstrument, MuteBehavior, SequenceChannel, SequenceLayer, SequencePlayer,
+};
+use std::convert::TryInto;
+
+// ported from gNoteFrequencies
+/// Playback rate multipliers of every semitone, relative to semitone 39.
+#[allow(clippy::approx_constant)]
+pub const NOTE_FREQUENCIES: [f32; 128] = [
+    0.105112, 0.111362, 0.117984, 0.125, 0.132433, 0.140308, 0.148651, 0.15749, 0.166855, 0.176777,
+    0.187288, 0.198425, 0.210224, 0.222725, 0.235969, 0.25, 0.264866, 0.280616, 0.297302, 0.31498,
+    0.33371, 0.353553, 0.374577, 0.39685, 0.420448, 0.445449, 0.471937, 0.5, 0.529732, 0.561231,
+    0.594604, 0.629961, 0.66742, 0.707107, 0.749154, 0.793701, 0.840897, 0.890899, 0.943875, 1.0,
+    1.059463, 1.122462, 1.189207, 1.259921, 1.33484, 1.414214, 1.498307, 1.587401, 1.681793,
+    1.781798, 1.887749, 2.0, 2.118926, 2.244924, 2.378414, 2.519842, 2.66968, 2.828428, 2.996615,
+    3.174803, 3.363586, 3.563596, 3.775498, 4.0, 4.237853, 4.489849, 4.756829, 5.039685, 5.33936,
+    5.656855, 5.993229, 6.349606, 6.727173, 7.127192, 7.550996, 8.0, 8.475705, 8.979697, 9.513658,
+    10.07937, 10.67872, 11.31371, 11.986459, 12.699211, 13.454346, 14.254383, 15.101993, 16.0,
+    16.95141, 17.959394, 19.027315, 20.15874, 21.35744, 22.62742, 23.972918, 25.398422, 26.908691,
+    28.508766, 30.203985, 32.0, 33.90282, 35.91879, 38.05463, 40.31748, 42.71488, 45.25484,
+    47.945835, 50.796844, 53.817383, 57.017532, 60.40797, 64.0, 67.80564, 71.83758, 76.10926,
+    80.63496, 85.42976, 45.25484, 47.945835, 50.796844, 53.817383, 57.017532, 60.40797, 64.0,
+    67.80564, 71.83758, 76.10926, 80.63496,
+];
+
+/// Envelope level lost per frame for every unit of release rate.
+const RELEASE_RATE_SCALE: f32 = 3.0 / 1280.0;
+
+/// Renders a sequence player's notes as interleaved stereo samples.
+#[derive(Debug)]
+pub struct Synthesizer<'a> {
+    ctl: &'a Ctl,
+    table: &'a SampleTable<'a>,
+    output_rate: u32,
+    voices: Vec<Voice<'a>>,
+    /// Output frames left until the player's next tick.
+    frames_until_tick: f64,
+}
+
+impl<'a> Synthesizer<'a> {
+    pub fn new(ctl: &'a Ctl, table: &'a SampleTable<'a>, output_rate: u32) -> Self {
+        Self {
+            ctl,
+            table,
+            output_rate,
+            voices: Vec::new(),
+            frames_until_tick: 0.0,
+        }
+    }
+
+    /// Whether any note is still sounding, which may be the case for a while once the sequence
+    /// has finished.
+    pub fn is_playing(&self) -> bool {
+        !self.voices.is_empty()
+    }
+
+    /// Fills the buffer with interleaved stereo frames, processing the player whenever one of
+    /// its ticks is due.
+    pub fn render(&mut self, player: &mut SequencePlayer, out: &mut [f32]) {
+        for frame in out.chunks_exact_mut(2) {
+            if self.frames_until_tick <= 0.0 {
+                if !player.finished {
+                    player.process();
+                }
+                let frames_per_tick =
+                    self.output_rate as f64 / player.session.updates_per_second() as f64;
+                self.update(player, frames_per_tick);
+                self.frames_until_tick += frames_per_tick;
+            }
+            self.frames_until_tick -= 1.0;
+
+            let mut mixed = [0.0; 2];
+            for voice in &mut self.voices {
+                let value = voice.next_value();
+                for (mixed, (volume, step)) in mixed
+                    .iter_mut()
+                    .zip(voice.volumes.iter_mut().zip(&voice.volume_steps))
+                {
+                    *mixed += value * *volume;
+                    *volume += step;
+                }
+            }
+            frame[0] = mixed[0].clamp(-1.0, 1.0);
+            frame[1] = mixed[1].clamp(-1.0, 1.0);
+        }
+    }
+
+    /// Starts and releases voices to match the player's layers, and moves their envelopes
+    /// forward by a tick.
+    fn update(&mut self, player: &SequencePlayer, frames_per_tick: f64) {
+        let session = player.session;
+        let updates_per_frame = session.updates_per_frame();
+        let rate_scale = session.ai_frequency() as f64 / self.output_rate as f64;
+
+        for voice in &mut self.voices {
+            voice.held = false;
+        }
+
+        let stopped = player.finished
+            || (player.muted && player.mute_behavior.contains(MuteBehavior::STOP_NOTES));
+        let channels = player
+            .channels
+            .iter()
+            .enumerate()
+            .filter_map(|(i, channel)| Some((i, channel.as_deref()?)))
+            .filter(|(_, channel)| channel.enabled && !stopped);
+        for (i, channel) in channels {
+            let layers = channel
+                .layers
+                .iter()
+                .enumerate()
+                .filter_map(|(j, layer)| Some((j, layer.as_deref()?)))
+                .filter(|(_, layer)| layer.enabled);
+            for (j, layer) in layers {
+                let pitch = match layer.pitch {
+                    Some(pitch) => pitch,
+                    None => continue,
+                };
+
+                let existing = self.voices.iter().position(|voice| {
+                    voice.channel == i
+                        && voice.layer == j
+                        && voice.note_count == layer.note_count
+                        && !voice.released
+                });
+                let index = match existing {
+                    Some(index) => index,
+                    None => {
+                        if !self.make_room(session.max_simultaneous_notes as usize) {
+                            continue;
+                        }
+                        let note = match self.resolve(player, channel, layer, pitch) {
+                            Some(note) => note,
+                            None => continue,
+                        };
+                        match Voice::new(note, i, j, layer.note_count) {
+                            Some(voice) => {
+                                self.voices.push(voice);
+                                self.voices.len() - 1
+                            }
+                            None => continue,
+                        }
+                    }
+                };
+
+                let voice = &mut self.voices[index];
+                voice.held = true;
+                voice.step = voice.freq_scale as f64 * channel.freq_scale as f64 * rate_scale;
+
+                let mut gain = layer.velocity_square / (127.0 * 127.0)
+                    * channel.volume
+                    * channel.volume_scale
+                    * player.volume
+                    * player.fade_volume;
+                if player.muted && player.mute_behavior.contains(MuteBehavior::SOFTEN) {
+                    gain *= player.mute_volume_scale;
+                }
+                voice.gain = gain;
+
+                let weight = channel.pan_channel_weight;
+                let layer_pan = voice.pan.unwrap_or(layer.pan);
+                voice.panning = channel.pan * weight + layer_pan * (1.0 - weight);
+            }
+        }
+
+        for voice in &mut self.voices {
+            if !voice.held && !voice.released {
+                voice.release(updates_per_frame);
+            }
+            voice.update(updates_per_frame, frames_per_tick);
+        }
+        self.voices.retain(|voice| !voice.is_finished());
+    }
+
+    // simplified from alloc_note, which may also steal notes of a lower priority
+    /// Makes sure a voice can be started without going over the limit, by stopping the oldest
+    /// of the released ones if needed. Returns false if every voice is still held.
+    fn make_room(&mut self, max_voices: usize) -> bool {
+        if self.voices.len() < max_voices {
+            return true;
+        }
+        match self.voices.iter().position(|voice| voice.released) {
+            Some(index) => {
+                self.voices.remove(index);
+                true
+            }
+            None => false,
+        }
+    }
+
+    // ported from seq_channel_layer_process_script and instrument_get_audio_bank_sound
+    /// The sound a note plays, along with what it's played with.
+    fn resolve(
+        &self,
+        player: &SequencePlayer,
+        channel: &SequenceChannel,
+        layer: &SequenceLayer,
+        pitch: u8,
+    ) -> Option<Note<'a>> {
+        let bank_id = channel.bank_id as usize;
+        let bank: &'a Bank = self.ctl.bank(bank_id).ok()?;
+        let table_bank = self.table.bank(bank_id).ok()?;
+
+        // drums aren't transposed by the sequence, nor overridden by the layer's instrument
+        let semitone = pitch as i16 + channel.transposition + layer.transposition;
+        let instrument = match (channel.instrument, layer.instrument) {
+            (ChannelInstrument::Drums, _) | (_, None) => channel.instrument,
+            (_, Some(instrument)) => instrument,
+        };
+        match instrument {
+            ChannelInstrument::Drums => {
+                let drum = bank.drum(semitone.try_into().ok()?)?;
+                let sound = drum.sound.as_ref()?;
+                Some(Note {
+                    bank,
+                    table_bank,
+                    sound,
+                    envelope: bank.envelope(drum.envelope),
+                    release_rate: drum.release_rate,
+                    pan: Some(drum.pan as f32 / 128.0),
+                    freq_scale: sound.tuning,
+                })
+            }
+            ChannelInstrument::Instrument(id) => {
+                let semitone: u8 = semitone
+                    .checked_add(player.transposition)?
+                    .try_into()
+                    .ok()?;
+                let frequency = NOTE_FREQUENCIES.get(semitone as usize)?;
+                let instrument = bank.instrument(id)?;
+                let sound = instrument.sound(semitone)?;
+                Some(Note {
+                    bank,
+                    table_bank,
+                    sound,
+                    envelope: bank.envelope(instrument.envelope),
+                    release_rate: instrument.release_rate,
+                    pan: None,
+                    freq_scale: sound.tuning * frequency,
+                })
+            }
+            ChannelInstrument::Wave(_) | ChannelInstrument::None => None,
+        }
+    }
+}
+
+/// What a note plays, out of its channel's bank.
+struct Note<'a> {
+    bank: &'a Bank,
+    table_bank: &'a [u8],
+    sound: &'a Sound,
+    envelope: &'a Envelope,
+    release_rate: u8,
+    /// Only drums have their own pan.
+    pan: Option<f32>,
+    /// Playback rate relative to the output frequency of the game.
+    freq_scale: f32,
+}
+
+/// A note being played.
+#[derive(Debug)]
+struct Voice<'a> {
+    channel: usize,
+    layer: usize,
+    note_count: u32,
+    /// Whether the note's layer still plays it, as of the last tick.
+    held: bool,
+    released: bool,
+
+    samples: vadpcm::Samples<'a>,
+    /// The samples the playback position is between, as a fraction of the way from `previous`
+    /// to `next`.
+    previous: f32,
+    next: f32,
+    phase: f64,
+    ended: bool,
+    freq_scale: f32,
+    /// Input samples per output frame.
+    step: f64,
+
+    adsr: Adsr<'a>,
+    release_rate: u8,
+    pan: Option<f32>,
+    gain: f32,
+    /// From 0 to 1, left to right.
+    panning: f32,
+    /// Volume of each output channel, moving by `volume_steps` every frame to reach the next
+    /// tick's volumes.
+    volumes: [f32; 2],
+    volume_steps: [f32; 2],
+}
+
+impl<'a> Voice<'a> {
+    /// Starts a note, unless its sample can't be decoded.
+    fn new(note: Note<'a>, channel: usize, layer: usize, note_count: u32) -> Option<Self> {
+        let sample = note.bank.sample(note.sound);
+        let samples = sample.samples(note.table_bank).ok()?;
+
+        let mut voice = Self {
+            channel,
+            layer,
+            note_count,
+            held: true,
+            released: false,
+            samples,
+            previous: 0.0,
+            next: 0.0,
+            phase: 0.0,
+            ended: false,
+            freq_scale: note.freq_scale,
+            step: 0.0,
+            adsr: Adsr::new(note.envelope),
+            release_rate: note.release_rate,
+            pan: note.pan,
+            gain: 0.0,
+            panning: 0.5,
+            volumes: [0.0; 2],
+            volume_steps: [0.0; 2],
+        };
+        voice.advance();
+        voice.advance();
+        Some(voice)
+    }
+
+    /// Moves on to the next input sample.
+    fn advance(&mut self) {
+        self.previous = self.next;
+        match self.samples.next() {
+            Some(value) => self.next = value as f32 / 32768.0,
+            None => {
+                self.next = 0.0;
+                self.ended = true;
+            }
+        }
+    }
+
+    /// The value at the playback position, moving it forward by a frame.
+    fn next_value(&mut self) -> f32 {
+        if self.phase >= 1.0 {
+            // past the end of the sample
+            return 0.0;
+        }
+        let value = self.previous + (self.next - self.previous) * self.phase as f32;
+        self.phase += self.step;
+        while self.phase >= 1.0 && !self.ended {
+            self.phase -= 1.0;
+            self.advance();
+        }
+        value
+    }
+
+    // ported from seq_channel_layer_decay_release_internal
+    fn release(&mut self, updates_per_frame: u32) {
+        self.released = true;
+        let fade_out = if self.release_rate == 0 {
+            1.0
+        } else {
+            self.release_rate as f32 * RELEASE_RATE_SCALE
+        };
+        self.adsr.release(fade_out / updates_per_frame as f32);
+    }
+
+    fn update(&mut self, updates_per_frame: u32, frames_per_tick: f64) {
+        let level = self.adsr.update(updates_per_frame) * self.gain;
+        // equal-power panning
+        let angle = self.panning.clamp(0.0, 1.0) * std::f32::consts::FRAC_PI_2;
+        let targets = [level * angle.cos(), level * angle.sin()];
+        for ((volume, step), target) in self
+            .volumes
+            .iter()
+            .zip(&mut self.volume_steps)
+            .zip(&targets)
+        {
+            *step = (target - volume) / frames_per_tick as f32;
+        }
+    }
+
+    /// Whether the note is over. Notes are kept until released even if they're silent, so that
+    /// they don't start over.
+    fn is_finished(&self) -> bool {
+        self.released && (self.ended || self.adsr.state == AdsrState::Disabled)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum AdsrState {
+    /// Going through the envelope's commands.
+    Loop,
+    /// Going linearly towards a point.
+    Fade,
+    Hang,
+    Release,
+    Disabled,
+}
+
+/// The volume envelope of a note.
+#[derive(Debug)]
+struct Adsr<'a> {
+    cmds: &'a [EnvelopeCmd],
+    index: usize,
+    state: AdsrState,
+    current: f32,
+    velocity: f32,
+    /// Ticks left until the current point is reached.
+    delay: u32,
+    /// Level lost every tick once released.
+    fade_out: f32,
+}
+
+impl<'a> Adsr<'a> {
+    fn new(envelope: &'a Envelope) -> Self {
+        Self {
+            cmds: &envelope.cmds,
+            index: 0,
+            state: AdsrState::Loop,
+            current: 0.0,
+            velocity: 0.0,
+            delay: 0,
+            fade_out: 0.0,
+        }
+    }
+
+    fn release(&mut self, fade_out: f32) {
+        if self.state != AdsrState::Disabled {
+            self.state = AdsrState::Release;
+            self.fade_out = fade_out;
+        }
+    }
+
+    // ported from adsr_update
+    /// Moves forward by a tick, returning the level.
+    fn update(&mut self, updates_per_frame: u32) -> f32 {
+        // envelopes that go back without any point in between would loop forever
+        for _ in 0..=self.cmds.len() {
+            if self.state != AdsrState::Loop {
+                break;
+            }
+            match self.cmds.get(self.index) {
+                Some(&EnvelopeCmd::Point { delay, volume }) => {
+                    let delay = delay as u32;
+                    // delays are in quarters of a frame, past the first few ticks
+                    let delay = if delay >= 4 {
+                        delay * updates_per_frame / 4
+                    } else {
+                        delay
+                    };
+                    let target = (volume as f32 / 32767.0).powi(2);
+                    self.delay = delay.max(1);
+                    self.velocity = (target - self.current) / self.delay as f32;
+                    self.state = AdsrState::Fade;
+                    self.index += 1;
+                }
+                Some(EnvelopeCmd::Hang) => self.state = AdsrState::Hang,
+                Some(&EnvelopeCmd::Goto(index)) => self.index = index as usize,
+                Some(EnvelopeCmd::Restart) => self.index = 0,
+                Some(EnvelopeCmd::Disable) | None => self.state = AdsrState::Disabled,
+            }
+        }
+
+        match self.state {
+            AdsrState::Fade => {
+                self.current += self.velocity;
+                self.delay -= 1;
+                if self.delay == 0 {
+                    self.state = AdsrState::Loop;
+                }
+            }
+            AdsrState::Release => {
+                self.current -= self.fade_out;
+                if self.current <= 0.0 {
+                    self.current = 0.0;
+                    self.state = AdsrState::Disabled;
+                }
+            }
+            AdsrState::Disabled => self.current = 0.0,
+            AdsrState::Loop | AdsrState::Hang => {}
+        }
+        self.current
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bank::{AdpcmBook, AdpcmLoop, Instrument, Sample};
+
+    /// A bank with a single instrument, looping a square wave forever, and its sample table.
+    fn test_bank() -> (Ctl, Vec<u8>) {
+        // residuals of 7 then -7, with no prediction
+        let frame = [0xb0, 0x77, 0x77, 0x77, 0x77, 0x99, 0x99, 0x99, 0x99];
+        let table_data = frame.repeat(4);
+
+        let sample = Sample {
+            addr: 0,
+            size: table_data.len() as u32,
+            adpcm_loop: AdpcmLoop {
+                start: 0,
+                end: 64,
+                count: u32::MAX,
+                state: Some([0; 16]),
+            },
+            book: AdpcmBook {
+                order: 2,
+                npredictors: 1,
+                book: vec![0; 16],
+            },
+        };
+        let instrument = Instrument {
+            normal_range_lo: 0,
+            normal_range_hi: 127,
+            release_rate: 0x20,
+            envelope: 0,
+            low_notes_sound: None,
+            normal_notes_sound: Some(Sound {
+                sample: 0,
+                tuning: 1.0,
+            }),
+            high_notes_sound: None,
+        };
+        let bank = Bank {
+            shared: 0,
+            date: 0,
+            instruments: vec![Some(instrument)],
+            drums: Vec::new(),
+            samples: vec![sample],
+            envelopes: vec![Envelope {
+                cmds: vec![
+                    EnvelopeCmd::Point {
+                        delay: 4,
+                        volume: 32767,
+                    },
+                    EnvelopeCmd::Hang,
+                ],
+            }],
+        };
+
+        let ctl = Ctl {
+            revision: 0,
+            banks: vec![bank],
+        };
+        (ctl, table_data)
+    }
+
+    /// Where the layer script of `sequence` starts.
+    const LAYER_ADDR: u16 = 0x1b;
+
+    /// A sequence playing the layer script on channel 0 with instrument 0, for far longer than
+    /// the tests last. Layer 1 plays it too if `twice` is set, and ends right away otherwise.
+    fn sequence(layer: &[u8], twice: bool) -> Vec<u8> {
+        let mut data = vec![
+            0xd7, 0x00, 0x01, // init channel 0
+            0xdb, 0x7f, // volume
+            0x90, 0x00, 0x0c, // start channel 0
+            0xfd, 0x8f, 0xff, // delay
+            0xff, // end
+            // channel
+            0xc4, // large notes
+            0xc1, 0x00, // instrument 0
+            0xdf, 0x7f, // volume
+            0x90, 0x00, 0x1b, // set layer 0
+            0x91, 0x00, 0x1a, // set layer 1, at the end below
+            0xfd, 0x8f, 0xff, // delay
+            0xff, // end
+        ];
+        if twice {
+            data[0x16] = LAYER_ADDR as u8;
+        }
+        data.extend_from_slice(layer);
+        data
+    }
+
+    /// Renders tick by tick until layer 0 of channel 0 gets to `pc`.
+    fn render_until(synth: &mut Synthesizer, player: &mut SequencePlayer, pc: u16) {
+        for _ in 0..10_000 {
+            synth.render(player, &mut [0.0; 2]);
+            let layer = player.channels[0]
+                .as_ref()
+                .and_then(|channel| channel.layers[0].as_ref());
+            if layer.map(|layer| layer.script_state.pc) == Some(pc) {
+                return;
+            }
+        }
+        panic!("layer never got to 0x{:x}", pc);
+    }
+
+    #[test]
+    fn rest_releases_note() {
+        let (ctl, table_data) = test_bank();
+        let table = SampleTable {
+            revision: 0,
+            banks: vec![&table_data],
+        };
+        let mut player = SequencePlayer::new();
+        // a tick every output frame
+        let mut synth = Synthesizer::new(&ctl, &table, player.session.updates_per_second());
+
+        let notes: [&[u8]; 2] = [
+            // note0 with a duration of 0, sounding up to the next command
+            &[0x27, 0x10, 0x7f, 0x00],
+            // note1, which has no duration
+            &[0x40 + 0x27, 0x10, 0x7f],
+        ];
+        for note in notes {
+            let note_end = LAYER_ADDR + note.len() as u16;
+            let mut layer = note.to_vec();
+            // rest, then end
+            layer.extend_from_slice(&[0xc0, 0x8f, 0xff, 0xff]);
+            player.load(sequence(&layer, false));
+            synth.voices.clear();
+
+            render_until(&mut synth, &mut player, note_end);
+            assert_eq!(synth.voices.len(), 1);
+            assert!(!synth.voices[0].released);
+
+            render_until(&mut synth, &mut player, note_end + 3);
+            assert_eq!(synth.voices.len(), 1);
+            assert!(synth.voices[0].released);
+        }
+    }
+
+    #[test]
+    fn voice_limit() {
+        let (ctl, table_data) = test_bank();
+        let table = SampleTable {
+            revision: 0,
+            banks: vec![&table_data],
+        };
+        let mut player = SequencePlayer::new();
+        let mut synth = Synthesizer::new(&ctl, &table, player.session.updates_per_second());
+
+        // note, rest, note, rest, end; on both layers
+        let layer = [
+            0x27, 0x10, 0x7f, 0x00, 0xc0, 0x10, 0x27, 0x10, 0x7f, 0x00, 0xc0, 0x8f, 0xff, 0xff,
+        ];
+        player.load(sequence(&layer, true));
+        player.session.max_simultaneous_notes = 1;
+
+        // the second layer's note doesn't get a voice
+        render_until(&mut synth, &mut player, LAYER_ADDR + 4);
+        assert_eq!(synth.voices.len(), 1);
+        assert!(!synth.voices[0].released);
+
+        render_until(&mut synth, &mut player, LAYER_ADDR + 6);
+        assert_eq!(synth.voices.len(), 1);
+        assert!(synth.voices[0].released);
+
+        // the next note takes over the released voice
+        render_until(&mut synth, &mut player, LAYER_ADDR + 10);
+        assert_eq!(synth.voices.len(), 1);
+        assert!(!synth.voices[0].released);
+    }
+}
@@ -0,0 +1,61 @@
+use crate::error::*;
+
+/// The set of predictors used to encode a sample.
+///
+/// Every predictor is made of `order` rows of 8 coefficients in 5.11 fixed point: row `i`
+/// holds the contribution of the `order - i`th previous sample to each of the next 8 samples,
+/// and the last row also holds the contribution of the residuals decoded so far.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Codebook {
+    order: usize,
+    npredictors: usize,
+    coefs: Vec<i16>,
+}
+
+impl Codebook {
+    /// Up to 16 predictors, since their index is stored in 4 bits.
+    pub const MAX_PREDICTORS: usize = 16;
+    pub const MAX_ORDER: usize = 8;
+
+    pub fn new(order: usize, npredictors: usize, coefs: Vec<i16>) -> Result<Self> {
+        if order == 0
+            || order > Self::MAX_ORDER
+            || npredictors == 0
+            || npredictors > Self::MAX_PREDICTORS
+        {
+            return Err(VadpcmError::InvalidCodebook { order, npredictors });
+        }
+
+        let expected = order * npredictors * 8;
+        if coefs.len() != expected {
+            return Err(VadpcmError::InvalidCodebookSize {
+                expected,
+                actual: coefs.len(),
+            });
+        }
+
+        Ok(Self {
+            order,
+            npredictors,
+            coefs,
+        })
+    }
+
+    pub fn order(&self) -> usize {
+        self.order
+    }
+
+    pub fn npredictors(&self) -> usize {
+        self.npredictors
+    }
+
+    /// All the coefficients, predictor after predictor.
+    pub fn coefs(&self) -> &[i16] {
+        &self.coefs
+    }
+
+    /// The `order * 8` coefficients of one predictor.
+    pub fn predictor(&self, i: usize) -> &[i16] {
+        &self.coefs[i * self.order * 8..][..self.order * 8]
+    }
+}
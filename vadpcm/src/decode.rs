@@ -0,0 +1,246 @@
+use crate::*;
+
+/// Decodes 8 samples out of their residuals (already scaled), given the predictor's
+/// coefficients and the `order` previously decoded samples.
+pub(crate) fn decode_half(
+    coefs: &[i16],
+    order: usize,
+    history: &[i16],
+    ins: &[i32; 8],
+) -> [i16; 8] {
+    let last_row = &coefs[(order - 1) * 8..][..8];
+    let mut out = [0; 8];
+
+    for j in 0..8 {
+        let mut acc = (ins[j] as i64) << 11;
+        for (i, &sample) in history.iter().enumerate() {
+            acc += coefs[i * 8 + j] as i64 * sample as i64;
+        }
+        for k in 0..j {
+            acc += last_row[j - k - 1] as i64 * ins[k] as i64;
+        }
+        out[j] = (acc >> 11).max(i16::MIN as i64).min(i16::MAX as i64) as i16;
+    }
+
+    out
+}
+
+/// Decodes frames one after the other, keeping track of the previous samples.
+#[derive(Debug, Clone)]
+pub struct Decoder {
+    book: Codebook,
+    format: FrameFormat,
+    state: [i16; 16],
+}
+
+impl Decoder {
+    pub fn new(book: Codebook, format: FrameFormat) -> Self {
+        Self {
+            book,
+            format,
+            state: [0; 16],
+        }
+    }
+
+    pub fn book(&self) -> &Codebook {
+        &self.book
+    }
+
+    pub fn format(&self) -> FrameFormat {
+        self.format
+    }
+
+    /// The last 16 decoded samples.
+    pub fn state(&self) -> &[i16; 16] {
+        &self.state
+    }
+
+    /// Restores the decoder state, e.g. from a loop.
+    pub fn set_state(&mut self, state: &[i16; 16]) {
+        self.state = *state;
+    }
+
+    fn predictor_index(&self, header: u8, frame: usize) -> Result<usize> {
+        let predictor = (header & 0x0f) as usize;
+        if predictor >= self.book.npredictors() {
+            return Err(VadpcmError::InvalidPredictor { frame, predictor });
+        }
+        Ok(predictor)
+    }
+
+    /// Checks that every frame in the data only uses existing predictors, so that decoding it
+    /// can't fail. Returns the amount of frames.
+    pub fn validate(&self, data: &[u8]) -> Result<usize> {
+        let frames = data.chunks_exact(self.format.frame_size());
+        let count = frames.len();
+        for (i, frame) in frames.enumerate() {
+            self.predictor_index(frame[0], i)?;
+        }
+        Ok(count)
+    }
+
+    /// Decodes a single frame of `format.frame_size()` bytes; shorter ones are an error. `index` is
+    /// only used for error reporting.
+    pub fn decode_frame(&mut self, frame: &[u8], index: usize) -> Result<[i16; 16]> {
+        let frame_size = self.format.frame_size();
+        if frame.len() < frame_size {
+            return Err(VadpcmError::ShortFrame {
+                frame: index,
+                expected: frame_size,
+                actual: frame.len(),
+            });
+        }
+
+        let header = frame[0];
+        let scale = 1i32 << (header >> 4);
+        let predictor = self.predictor_index(header, index)?;
+
+        let order = self.book.order();
+        let coefs = self.book.predictor(predictor);
+        let bits = self.format.residual_bits();
+        let per_byte = 8 / bits as usize;
+
+        let mut out = [0; 16];
+        for half in 0..2 {
+            let mut ins = [0; 8];
+            for (i, value) in ins.iter_mut().enumerate() {
+                let n = half * 8 + i;
+                let byte = frame[1 + n / per_byte];
+                let shift = 8 - bits * (n % per_byte + 1) as u32;
+                // move the residual to the top of the byte to sign-extend it
+                let residual = ((byte >> shift) << (8 - bits)) as i8 >> (8 - bits);
+                *value = residual as i32 * scale;
+            }
+
+            let history = if half == 0 {
+                &self.state[16 - order..]
+            } else {
+                &out[8 - order..8]
+            };
+            let decoded = decode_half(coefs, order, history, &ins);
+            out[half * 8..][..8].copy_from_slice(&decoded);
+        }
+
+        self.state = out;
+        Ok(out)
+    }
+
+    /// Decodes every whole frame in the data.
+    pub fn decode(&mut self, data: &[u8]) -> Result<Vec<i16>> {
+        let mut samples = Vec::with_capacity(data.len() / self.format.frame_size() * 16);
+        for (i, frame) in data.chunks_exact(self.format.frame_size()).enumerate() {
+            samples.extend_from_slice(&self.decode_frame(frame, i)?);
+        }
+        Ok(samples)
+    }
+
+    /// Decodes the data sample by sample.
+    pub fn samples(self, data: &[u8]) -> Result<Samples<'_>> {
+        Samples::new(self, data)
+    }
+}
+
+/// An iterator over decoded samples, going back to the loop start as many times as needed.
+#[derive(Debug, Clone)]
+pub struct Samples<'a> {
+    decoder: Decoder,
+    data: &'a [u8],
+    adpcm_loop: Option<Loop>,
+    loops_remaining: u32,
+    /// Position of the next sample.
+    position: u32,
+    end: u32,
+    frame: [i16; 16],
+    frame_index: Option<usize>,
+}
+
+impl<'a> Samples<'a> {
+    pub fn new(decoder: Decoder, data: &'a [u8]) -> Result<Self> {
+        let frames = decoder.validate(data)?;
+
+        Ok(Self {
+            decoder,
+            data,
+            adpcm_loop: None,
+            loops_remaining: 0,
+            position: 0,
+            end: (frames * SAMPLES_PER_FRAME) as u32,
+            frame: [0; 16],
+            frame_index: None,
+        })
+    }
+
+    /// Sets the loop to play. A loop with a count of 0 ends the sample at its end instead.
+    pub fn with_loop(mut self, adpcm_loop: Loop) -> Self {
+        if adpcm_loop.count == 0 {
+            self.end = self.end.min(adpcm_loop.end);
+        } else {
+            self.loops_remaining = adpcm_loop.count;
+            self.adpcm_loop = Some(adpcm_loop);
+        }
+        self
+    }
+
+    /// Position of the next sample.
+    pub fn position(&self) -> u32 {
+        self.position
+    }
+}
+
+impl<'a> Iterator for Samples<'a> {
+    type Item = i16;
+
+    fn next(&mut self) -> Option<i16> {
+        if let Some(adpcm_loop) = &self.adpcm_loop {
+            if self.position == adpcm_loop.end && self.loops_remaining != 0 {
+                if self.loops_remaining != INFINITE_LOOP {
+                    self.loops_remaining -= 1;
+                }
+
+                // decode the frame holding the loop start from the saved state
+                self.decoder.set_state(&adpcm_loop.state);
+                self.position = adpcm_loop.start;
+                self.frame_index = None;
+            }
+        }
+
+        if self.position >= self.end {
+            return None;
+        }
+
+        let frame_index = self.position as usize / SAMPLES_PER_FRAME;
+        if self.frame_index != Some(frame_index) {
+            let frame_size = self.decoder.format().frame_size();
+            let frame = &self.data[frame_index * frame_size..][..frame_size];
+            // frames have already been validated
+            self.frame = self.decoder.decode_frame(frame, frame_index).ok()?;
+            self.frame_index = Some(frame_index);
+        }
+
+        let sample = self.frame[self.position as usize % SAMPLES_PER_FRAME];
+        self.position += 1;
+        Some(sample)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn short_frame_is_an_error() {
+        let book = Codebook::new(2, 1, vec![0; 16]).unwrap();
+        let mut decoder = Decoder::new(book, FrameFormat::Bits4);
+
+        assert!(matches!(
+            decoder.decode_frame(&[0; 5], 3),
+            Err(VadpcmError::ShortFrame {
+                frame: 3,
+                expected: 9,
+                actual: 5
+            })
+        ));
+        assert!(decoder.decode_frame(&[], 0).is_err());
+        assert_eq!(decoder.decode_frame(&[0; 9], 0).unwrap(), [0; 16]);
+    }
+}
@@ -0,0 +1,274 @@
+//! Codebook design, in the spirit of the SDK's `tabledesign`.
+//!
+//! Every frame of the input gets its own optimal predictor out of its autocorrelation, then
+//! those are clustered into the requested amount of predictors by repeatedly splitting and
+//! refining them, measuring how well a predictor fits a frame by its prediction error energy.
+
+use crate::*;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct DesignOptions {
+    pub order: usize,
+    /// The codebook will have `2^predictor_bits` predictors, so this can be at most 4.
+    pub predictor_bits: u32,
+    /// Amount of refinement passes after each split.
+    pub refine_iterations: usize,
+    /// Frames with less energy than this are ignored.
+    pub threshold: f64,
+}
+
+impl Default for DesignOptions {
+    fn default() -> Self {
+        Self {
+            order: 2,
+            predictor_bits: 2,
+            refine_iterations: 2,
+            threshold: 10.0,
+        }
+    }
+}
+
+/// Autocorrelation statistics of a frame, enough to compute the prediction error energy of any
+/// predictor over it.
+#[derive(Debug, Clone)]
+struct FrameStats {
+    /// `r[i][j]`: sum of `x[n - i] * x[n - j]` over the frame, for `i, j` in `0..=order`.
+    r: Vec<Vec<f64>>,
+}
+
+impl FrameStats {
+    fn new(history: &[f64], frame: &[f64], order: usize) -> Self {
+        let sample = |n: isize| {
+            if n < 0 {
+                history[(history.len() as isize + n) as usize]
+            } else {
+                frame[n as usize]
+            }
+        };
+
+        let mut r = vec![vec![0.0; order + 1]; order + 1];
+        for (i, row) in r.iter_mut().enumerate() {
+            for (j, value) in row.iter_mut().enumerate() {
+                *value = (0..frame.len() as isize)
+                    .map(|n| sample(n - i as isize) * sample(n - j as isize))
+                    .sum();
+            }
+        }
+        Self { r }
+    }
+
+    fn add(&mut self, other: &Self) {
+        for (row, other_row) in self.r.iter_mut().zip(&other.r) {
+            for (value, other_value) in row.iter_mut().zip(other_row) {
+                *value += other_value;
+            }
+        }
+    }
+
+    fn energy(&self) -> f64 {
+        self.r[0][0]
+    }
+
+    /// Energy of the prediction error `x[n] + a[1] x[n - 1] + ... + a[order] x[n - order]`,
+    /// with `a[0] = 1`.
+    fn error(&self, a: &[f64]) -> f64 {
+        let mut error = 0.0;
+        for (i, row) in self.r.iter().enumerate() {
+            for (j, value) in row.iter().enumerate() {
+                error += a[i] * a[j] * value;
+            }
+        }
+        error
+    }
+
+    /// The predictor minimizing the prediction error, if the frame isn't degenerate.
+    fn optimal_predictor(&self) -> Option<Vec<f64>> {
+        let order = self.r.len() - 1;
+        // solve R a = -r, where R is the lower right block and r the first column
+        let mut matrix: Vec<Vec<f64>> = (1..=order)
+            .map(|i| {
+                let mut row: Vec<f64> = (1..=order).map(|j| self.r[i][j]).collect();
+                row.push(-self.r[i][0]);
+                row
+            })
+            .collect();
+
+        let solution = solve(&mut matrix)?;
+        let mut a = vec![1.0];
+        a.extend(solution);
+        Some(a)
+    }
+}
+
+/// Solves a linear system given as an augmented matrix, with gaussian elimination.
+fn solve(matrix: &mut [Vec<f64>]) -> Option<Vec<f64>> {
+    let n = matrix.len();
+    for col in 0..n {
+        let pivot = (col..n).max_by(|&a, &b| {
+            matrix[a][col]
+                .abs()
+                .partial_cmp(&matrix[b][col].abs())
+                .unwrap()
+        })?;
+        if matrix[pivot][col].abs() < 1e-9 {
+            return None;
+        }
+        matrix.swap(col, pivot);
+
+        let (done, rest) = matrix.split_at_mut(col + 1);
+        let pivot_row = &done[col];
+        for row in rest {
+            let factor = row[col] / pivot_row[col];
+            for (value, pivot_value) in row[col..].iter_mut().zip(&pivot_row[col..]) {
+                *value -= factor * pivot_value;
+            }
+        }
+    }
+
+    let mut solution = vec![0.0; n];
+    for row in (0..n).rev() {
+        let sum: f64 = (row + 1..n).map(|k| matrix[row][k] * solution[k]).sum();
+        solution[row] = (matrix[row][n] - sum) / matrix[row][row];
+    }
+    Some(solution)
+}
+
+/// Only keeps predictors that are stable, i.e. whose reflection coefficients all have a
+/// magnitude below 1, since unstable ones make the decoder blow up.
+fn is_stable(a: &[f64]) -> bool {
+    // step-down recursion
+    let mut a = a.to_vec();
+    for m in (1..a.len()).rev() {
+        let k = a[m];
+        if k.abs() >= 1.0 {
+            return false;
+        }
+        let prev: Vec<f64> = (0..m)
+            .map(|i| (a[i] - k * a[m - i]) / (1.0 - k * k))
+            .collect();
+        a.truncate(m);
+        a.copy_from_slice(&prev);
+    }
+    true
+}
+
+/// The predictor minimizing the total prediction error over a set of frames.
+fn centroid(frames: &[&FrameStats], fallback: &[f64]) -> Vec<f64> {
+    let mut total = frames[0].clone();
+    for frame in &frames[1..] {
+        total.add(frame);
+    }
+    total
+        .optimal_predictor()
+        .filter(|a| is_stable(a))
+        .unwrap_or_else(|| fallback.to_vec())
+}
+
+/// Expands a predictor into the rows of 8 coefficients used by the decoder: the response of
+/// each of the next 8 samples to each of the `order` previous ones.
+fn expand(a: &[f64]) -> Vec<i16> {
+    let order = a.len() - 1;
+    let mut coefs = Vec::with_capacity(order * 8);
+
+    for i in 0..order {
+        // run the predictor with a single previous sample set to 1
+        let mut history = vec![0.0; order];
+        history[i] = 1.0;
+        for _ in 0..8 {
+            let next: f64 = -(1..=order).map(|k| a[k] * history[order - k]).sum::<f64>();
+            coefs.push(
+                (next * 2048.0)
+                    .round()
+                    .max(i16::MIN as f64)
+                    .min(i16::MAX as f64) as i16,
+            );
+            history.remove(0);
+            history.push(next);
+        }
+    }
+
+    coefs
+}
+
+impl Codebook {
+    /// Designs a codebook suited to the given samples.
+    pub fn design(samples: &[i16], options: &DesignOptions) -> Result<Self> {
+        let bits = options.predictor_bits;
+        if bits > Self::MAX_PREDICTORS.trailing_zeros() {
+            return Err(VadpcmError::InvalidPredictorBits { bits });
+        }
+
+        let order = options.order;
+        let npredictors = 1usize << bits;
+        if order == 0 || order > Self::MAX_ORDER || npredictors > Self::MAX_PREDICTORS {
+            return Err(VadpcmError::InvalidCodebook { order, npredictors });
+        }
+
+        let samples: Vec<f64> = samples.iter().map(|&s| s as f64).collect();
+        let zeros = [0.0; SAMPLES_PER_FRAME];
+        let frames: Vec<FrameStats> = samples
+            .chunks(SAMPLES_PER_FRAME)
+            .enumerate()
+            .map(|(i, frame)| {
+                let history = if i == 0 {
+                    &zeros[..]
+                } else {
+                    &samples[(i - 1) * SAMPLES_PER_FRAME..i * SAMPLES_PER_FRAME]
+                };
+                FrameStats::new(history, frame, order)
+            })
+            .filter(|frame| frame.energy() > options.threshold)
+            .filter(|frame| frame.optimal_predictor().is_some_and(|a| is_stable(&a)))
+            .collect();
+
+        // a predictor that predicts nothing
+        let mut silence = vec![0.0; order + 1];
+        silence[0] = 1.0;
+
+        let mut predictors = vec![if frames.is_empty() {
+            silence.clone()
+        } else {
+            centroid(&frames.iter().collect::<Vec<_>>(), &silence)
+        }];
+
+        while predictors.len() < npredictors {
+            predictors = predictors
+                .iter()
+                .flat_map(|a| {
+                    let perturb = |delta: f64| {
+                        let mut b = a.clone();
+                        for value in &mut b[1..] {
+                            *value *= 1.0 + delta;
+                        }
+                        b
+                    };
+                    vec![perturb(0.01), perturb(-0.01)]
+                })
+                .collect();
+
+            for _ in 0..options.refine_iterations {
+                let mut clusters = vec![Vec::new(); predictors.len()];
+                for frame in &frames {
+                    let best = (0..predictors.len())
+                        .min_by(|&a, &b| {
+                            frame
+                                .error(&predictors[a])
+                                .partial_cmp(&frame.error(&predictors[b]))
+                                .unwrap()
+                        })
+                        .unwrap();
+                    clusters[best].push(frame);
+                }
+
+                for (predictor, cluster) in predictors.iter_mut().zip(&clusters) {
+                    if !cluster.is_empty() {
+                        *predictor = centroid(cluster, predictor);
+                    }
+                }
+            }
+        }
+
+        let coefs = predictors.iter().flat_map(|a| expand(a)).collect();
+        Self::new(order, npredictors, coefs)
+    }
+}
@@ -0,0 +1,250 @@
+use crate::decode::decode_half;
+use crate::*;
+
+/// Largest scale tried, as residuals scaled further than that would exceed 16 bits anyway.
+const MAX_SCALE: u8 = 12;
+
+/// Encodes frames one after the other, keeping track of what the decoder will have decoded so
+/// far, so that quantization errors don't accumulate.
+#[derive(Debug, Clone)]
+pub struct Encoder {
+    book: Codebook,
+    format: FrameFormat,
+    state: [i16; 16],
+}
+
+/// The outcome of encoding a frame with a given predictor and scale.
+struct Candidate {
+    header: u8,
+    residuals: [i8; 16],
+    decoded: [i16; 16],
+    error: i64,
+}
+
+impl Encoder {
+    pub fn new(book: Codebook, format: FrameFormat) -> Self {
+        Self {
+            book,
+            format,
+            state: [0; 16],
+        }
+    }
+
+    pub fn book(&self) -> &Codebook {
+        &self.book
+    }
+
+    /// The last 16 samples, as the decoder will decode them.
+    pub fn state(&self) -> &[i16; 16] {
+        &self.state
+    }
+
+    fn try_encode(&self, samples: &[i16; 16], predictor: usize, scale: u8) -> Candidate {
+        let order = self.book.order();
+        let coefs = self.book.predictor(predictor);
+        let last_row = &coefs[(order - 1) * 8..][..8];
+        let bits = self.format.residual_bits();
+        let max = (1 << (bits - 1)) - 1;
+        let min = -(1 << (bits - 1));
+        let factor = 1i64 << scale;
+
+        let mut residuals = [0; 16];
+        let mut decoded = [0; 16];
+        for half in 0..2 {
+            let history = if half == 0 {
+                &self.state[16 - order..]
+            } else {
+                &decoded[8 - order..8]
+            };
+
+            let mut ins = [0; 8];
+            for j in 0..8 {
+                // what the decoder predicts before adding this sample's residual
+                let mut prediction = 0;
+                for (i, &sample) in history.iter().enumerate() {
+                    prediction += coefs[i * 8 + j] as i64 * sample as i64;
+                }
+                for k in 0..j {
+                    prediction += last_row[j - k - 1] as i64 * ins[k] as i64;
+                }
+
+                let target = (samples[half * 8 + j] as i64) << 11;
+                let residual = (target - prediction) as f64 / (2048 * factor) as f64;
+                let residual = (residual.round() as i64).max(min).min(max);
+                residuals[half * 8 + j] = residual as i8;
+                ins[j] = (residual * factor) as i32;
+            }
+
+            let out = decode_half(coefs, order, history, &ins);
+            decoded[half * 8..][..8].copy_from_slice(&out);
+        }
+
+        let error = samples
+            .iter()
+            .zip(&decoded)
+            .map(|(&a, &b)| (a as i64 - b as i64).pow(2))
+            .sum();
+
+        Candidate {
+            header: (scale << 4) | predictor as u8,
+            residuals,
+            decoded,
+            error,
+        }
+    }
+
+    /// Encodes a frame of 16 samples, picking the predictor and scale that minimize the error.
+    pub fn encode_frame(&mut self, samples: &[i16; 16]) -> Vec<u8> {
+        let best = (0..self.book.npredictors())
+            .flat_map(|predictor| (0..=MAX_SCALE).map(move |scale| (predictor, scale)))
+            .map(|(predictor, scale)| self.try_encode(samples, predictor, scale))
+            .min_by_key(|candidate| candidate.error)
+            .unwrap();
+
+        let bits = self.format.residual_bits();
+        let per_byte = 8 / bits as usize;
+        let mask = (1u8 << bits) - 1;
+
+        let mut frame = vec![0; self.format.frame_size()];
+        frame[0] = best.header;
+        for (n, &residual) in best.residuals.iter().enumerate() {
+            let shift = 8 - bits * (n % per_byte + 1) as u32;
+            frame[1 + n / per_byte] |= (residual as u8 & mask) << shift;
+        }
+
+        self.state = best.decoded;
+        frame
+    }
+
+    /// Encodes samples, padding the last frame with silence.
+    pub fn encode(&mut self, samples: &[i16]) -> Vec<u8> {
+        let frames = samples.len().div_ceil(SAMPLES_PER_FRAME);
+        let mut data = Vec::with_capacity(frames * self.format.frame_size());
+
+        for chunk in samples.chunks(SAMPLES_PER_FRAME) {
+            let mut frame = [0; 16];
+            frame[..chunk.len()].copy_from_slice(chunk);
+            data.extend(self.encode_frame(&frame));
+        }
+
+        data
+    }
+
+    /// Encodes samples like `encode`, also computing the decoder state to restore when going
+    /// back to the start of the loop from `start` to `end` (in samples), repeated `count` times.
+    pub fn encode_looped(
+        &mut self,
+        samples: &[i16],
+        start: u32,
+        end: u32,
+        count: u32,
+    ) -> (Vec<u8>, Loop) {
+        // the state is the one right before the frame holding the loop start
+        let split = (start as usize / SAMPLES_PER_FRAME * SAMPLES_PER_FRAME).min(samples.len());
+        let mut data = self.encode(&samples[..split]);
+        let state = self.state;
+        data.extend(self.encode(&samples[split..]));
+
+        let adpcm_loop = Loop {
+            start,
+            end,
+            count,
+            state,
+        };
+        (data, adpcm_loop)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A decaying mix of two sines.
+    fn signal(len: usize) -> Vec<i16> {
+        (0..len)
+            .map(|n| {
+                let t = n as f64 / 32000.0;
+                let value = (t * 440.0 * std::f64::consts::TAU).sin() * 8000.0
+                    + (t * 1250.0 * std::f64::consts::TAU).sin() * 3000.0;
+                (value * (-t * 2.0).exp()) as i16
+            })
+            .collect()
+    }
+
+    fn max_error(a: &[i16], b: &[i16]) -> i32 {
+        a.iter()
+            .zip(b)
+            .map(|(&a, &b)| (a as i32 - b as i32).abs())
+            .max()
+            .unwrap()
+    }
+
+    #[test]
+    fn round_trip() {
+        let samples = signal(1600);
+        let book = Codebook::design(&samples, &DesignOptions::default()).unwrap();
+
+        for (format, bound) in [(FrameFormat::Bits4, 256), (FrameFormat::Bits2, 1024)] {
+            let data = Encoder::new(book.clone(), format).encode(&samples);
+            assert_eq!(data.len(), 100 * format.frame_size());
+
+            let decoded = Decoder::new(book.clone(), format).decode(&data).unwrap();
+            assert_eq!(decoded.len(), samples.len());
+            let error = max_error(&samples, &decoded);
+            assert!(error < bound, "{:?}: error {}", format, error);
+        }
+    }
+
+    #[test]
+    fn round_trip_looped() {
+        let samples = signal(1600);
+        let book = Codebook::design(&samples, &DesignOptions::default()).unwrap();
+        let (start, end) = (200, 1400);
+
+        let mut encoder = Encoder::new(book.clone(), FrameFormat::Bits4);
+        let (data, adpcm_loop) = encoder.encode_looped(&samples, start, end, 2);
+        assert_eq!(
+            data,
+            Encoder::new(book.clone(), FrameFormat::Bits4).encode(&samples)
+        );
+        assert_eq!((adpcm_loop.start, adpcm_loop.end), (start, end));
+
+        let decoded: Vec<i16> = Decoder::new(book, FrameFormat::Bits4)
+            .samples(&data)
+            .unwrap()
+            .with_loop(adpcm_loop)
+            .collect();
+
+        // once through up to the end, then twice more from the start, then the rest
+        let looped = &samples[start as usize..end as usize];
+        let mut expected = samples[..end as usize].to_vec();
+        expected.extend_from_slice(looped);
+        expected.extend_from_slice(looped);
+        expected.extend_from_slice(&samples[end as usize..]);
+        assert_eq!(decoded.len(), expected.len());
+        assert!(max_error(&expected, &decoded) < 256);
+    }
+
+    #[test]
+    fn too_many_predictor_bits() {
+        let options = DesignOptions {
+            predictor_bits: 64,
+            ..DesignOptions::default()
+        };
+        assert!(matches!(
+            Codebook::design(&signal(64), &options),
+            Err(VadpcmError::InvalidPredictorBits { bits: 64 })
+        ));
+
+        let options = DesignOptions {
+            predictor_bits: 4,
+            ..DesignOptions::default()
+        };
+        assert_eq!(
+            Codebook::design(&signal(64), &options)
+                .unwrap()
+                .npredictors(),
+            16
+        );
+    }
+}
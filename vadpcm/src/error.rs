@@ -0,0 +1,21 @@
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum VadpcmError {
+    #[error("invalid codebook with order {order} and {npredictors} predictors")]
+    InvalidCodebook { order: usize, npredictors: usize },
+    #[error("{bits} predictor bits is more than a codebook can have")]
+    InvalidPredictorBits { bits: u32 },
+    #[error("codebook should have {expected} coefficients, but has {actual}")]
+    InvalidCodebookSize { expected: usize, actual: usize },
+    #[error("frame {frame} uses predictor {predictor}, which does not exist")]
+    InvalidPredictor { frame: usize, predictor: usize },
+    #[error("frame {frame} should be {expected} bytes long, but is {actual}")]
+    ShortFrame {
+        frame: usize,
+        expected: usize,
+        actual: usize,
+    },
+}
+
+pub type Result<T> = std::result::Result<T, VadpcmError>;
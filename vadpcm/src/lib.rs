@@ -0,0 +1,66 @@
+//! Support for VADPCM, the compressed sample format used by N64 games.
+//!
+//! Samples are split into frames of 16 samples, each starting with a header byte holding a
+//! scale (upper 4 bits) and the index of the predictor to use (lower 4 bits), followed by the
+//! residuals as 4-bit (9-byte frames) or 2-bit (5-byte frames) values.
+//!
+//! To encode a sample, first design a codebook for it with `Codebook::design`, then feed it to
+//! an `Encoder`.
+
+mod error;
+pub use error::{Result, VadpcmError};
+
+mod codebook;
+pub use codebook::Codebook;
+
+mod decode;
+pub use decode::{Decoder, Samples};
+
+mod design;
+pub use design::DesignOptions;
+
+mod encode;
+pub use encode::Encoder;
+
+pub const SAMPLES_PER_FRAME: usize = 16;
+
+/// Loop count meaning that a loop repeats forever.
+pub const INFINITE_LOOP: u32 = u32::MAX;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FrameFormat {
+    /// 9-byte frames with 4-bit residuals, as used by almost every sample.
+    Bits4,
+    /// 5-byte frames with 2-bit residuals.
+    Bits2,
+}
+
+impl FrameFormat {
+    /// Size of a frame in bytes.
+    pub fn frame_size(self) -> usize {
+        match self {
+            FrameFormat::Bits4 => 9,
+            FrameFormat::Bits2 => 5,
+        }
+    }
+
+    pub(crate) fn residual_bits(self) -> u32 {
+        match self {
+            FrameFormat::Bits4 => 4,
+            FrameFormat::Bits2 => 2,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Loop {
+    /// Loop start, in samples.
+    pub start: u32,
+    /// Loop end, in samples.
+    pub end: u32,
+    /// Amount of times to loop, or `INFINITE_LOOP`.
+    pub count: u32,
+    /// The last 16 decoded samples before the frame holding the loop start, which the decoder
+    /// state is restored from when going back to it.
+    pub state: [i16; 16],
+}